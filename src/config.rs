@@ -1,12 +1,17 @@
+use crate::expression;
 use crate::karabiner::{
-    Condition, FromEvent, FromKeyCode, FromModifiers, FromSimultaneous, Manipulator,
-    ManipulatorParameters, Rule, SetVariable, SimpleModificationEntry, SimpleModificationKey,
-    SimultaneousKey, SimultaneousOptions, ToEvent, ToKeyCode, ToMouseKey, ToPointingButton,
-    ToSetVariable, ToShellCommand,
+    Condition, DeviceIdentifier, DoubleClick, FromAny, FromEvent, FromKeyCode, FromModifiers,
+    FromSimultaneous, InputSourceSpecifier, KeyOrder, KeyUpWhen, KeyUsage, KeyboardType,
+    Manipulator, ManipulatorParameters, MouseCursorPosition, NotificationMessage,
+    OpenApplication, Parameters, Rule, SetVariable, SimpleModificationEntry,
+    SimpleModificationKey, SimultaneousKey, SimultaneousOptions, SleepSystem, SoftwareFunction,
+    StickyModifierState, ToDelayedAction, ToEvent, ToEventOptions, ToKeyCode, ToMouseKey,
+    ToNotificationMessage, ToPointingButton, ToSelectInputSource, ToSetVariable, ToShellCommand,
+    ToSoftwareFunction, ToStickyModifier,
 };
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 /// User-facing config schema (simplified, declarative)
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +20,21 @@ pub struct UserConfig {
     pub profile: ProfileSettings,
     #[serde(default)]
     pub simlayers: HashMap<String, Simlayer>,
+    /// Layers active while their key is held down
+    #[serde(default)]
+    pub layers: HashMap<String, Layer>,
+    /// Modes that stay on after being entered, until an exit key or timeout
+    #[serde(default)]
+    pub modes: HashMap<String, Mode>,
+    /// Multi-stroke bindings, e.g. leader then `g` then `s`
+    #[serde(default)]
+    pub sequences: Vec<Sequence>,
+    /// Named devices that conditions can refer to
+    #[serde(default)]
+    pub devices: HashMap<String, Device>,
+    /// Named input sources that conditions and `select_input_source` can refer to
+    #[serde(default)]
+    pub input_sources: HashMap<String, InputSource>,
     #[serde(default)]
     pub simple: Vec<SimpleModification>,
     #[serde(default)]
@@ -23,16 +43,37 @@ pub struct UserConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimpleModification {
-    pub from: String,
-    pub to: String,
+    pub from: Key,
+    pub to: Key,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProfileSettings {
     #[serde(default = "default_alone")]
     pub alone: u32,
     #[serde(default = "default_sim")]
     pub sim: u32,
+    #[serde(default = "default_held")]
+    pub held: u32,
+    #[serde(default = "default_delay")]
+    pub delay: u32,
+    #[serde(default = "default_scroll_speed")]
+    pub scroll_speed: u32,
+    #[serde(default = "default_sequence")]
+    pub sequence: u32,
+}
+
+impl Default for ProfileSettings {
+    fn default() -> Self {
+        Self {
+            alone: default_alone(),
+            sim: default_sim(),
+            held: default_held(),
+            delay: default_delay(),
+            scroll_speed: default_scroll_speed(),
+            sequence: default_sequence(),
+        }
+    }
 }
 
 fn default_alone() -> u32 {
@@ -41,12 +82,252 @@ fn default_alone() -> u32 {
 fn default_sim() -> u32 {
     200
 }
+fn default_held() -> u32 {
+    500
+}
+fn default_delay() -> u32 {
+    500
+}
+fn default_scroll_speed() -> u32 {
+    100
+}
+fn default_sequence() -> u32 {
+    1000
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Simlayer {
-    pub key: String,
+    /// Layer key, or keys pressed together (e.g. d+f); may be empty if `modifiers` is set
+    #[serde(default)]
+    pub key: SimlayerKeys,
+    /// Modifiers that must be held with the layer key
+    #[serde(default)]
+    pub modifiers: Option<Modifiers>,
+    /// Modifiers allowed with the layer key (default: any, unless `modifiers` is set)
+    #[serde(default)]
+    pub optional: Option<Vec<String>>,
     #[serde(default)]
     pub threshold: Option<u32>,
+    /// Trigger key order (default: strict, the layer key first)
+    #[serde(default)]
+    pub key_down_order: Option<KeyOrder>,
+    /// Trigger release order (default: strict_inverse)
+    #[serde(default)]
+    pub key_up_order: Option<KeyOrder>,
+    /// Only trigger if no other key is pressed in between (default: true)
+    #[serde(default)]
+    pub detect_key_down_uninterruptedly: Option<bool>,
+    /// Sent when the layer is activated
+    #[serde(default)]
+    pub on_enter: Option<ToKey>,
+    /// Sent when the layer is released
+    #[serde(default)]
+    pub on_exit: Option<ToKey>,
+    /// Only enable the layer where this holds, e.g. in some apps or on some devices
+    #[serde(default)]
+    pub condition: Option<UserCondition>,
+    /// Show a notification while the layer is active
+    #[serde(default)]
+    pub indicator: Option<Indicator>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Layer {
+    pub key: Key,
+    /// Sent when the key is tapped (default: the key itself)
+    #[serde(default)]
+    pub alone: Option<ToKey>,
+    /// Show a notification while the layer is active
+    #[serde(default)]
+    pub indicator: Option<Indicator>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mode {
+    /// Variable set while the mode is on (default: the mode name)
+    #[serde(default)]
+    pub variable: Option<String>,
+    /// Keys that turn the mode on
+    pub enter: Vec<FromKey>,
+    /// Keys that turn the mode off
+    #[serde(default = "default_mode_exit")]
+    pub exit: Vec<FromKey>,
+    /// Leave the mode after this many ms without a key press (requires `Unmapped::Swallow`);
+    /// a key handled by a rule outside the mode cancels the timeout
+    #[serde(default)]
+    pub timeout: Option<u32>,
+    /// What happens to unmodified keys the mode doesn't map
+    #[serde(default)]
+    pub unmapped: Unmapped,
+    /// Show a notification while the mode is on
+    #[serde(default)]
+    pub indicator: Option<Indicator>,
+}
+
+fn default_mode_exit() -> Vec<FromKey> {
+    vec![FromKey::Simple(Key::Name("escape".to_string()))]
+}
+
+impl Mode {
+    fn variable<'a>(&'a self, name: &'a str) -> &'a str {
+        self.variable.as_deref().unwrap_or(name)
+    }
+
+    /// Actions that turn the mode on
+    fn enter_action(&self, name: &str) -> ToKey {
+        let mut actions = vec![ToKey::SetVariable {
+            set_variable: SetVariable {
+                name: self.variable(name).to_string(),
+                value: 1.into(),
+            },
+        }];
+        if let Some(text) = self.indicator.as_ref().and_then(|i| i.text(name)) {
+            actions.push(ToKey::Notify {
+                notify: text,
+                id: Some(name.to_string()),
+            });
+        }
+        ToKey::Multiple(actions)
+    }
+
+    /// Actions that turn the mode off
+    fn exit_action(&self, name: &str) -> ToKey {
+        let mut actions = vec![ToKey::SetVariable {
+            set_variable: SetVariable {
+                name: self.variable(name).to_string(),
+                value: 0.into(),
+            },
+        }];
+        if self.indicator.as_ref().and_then(|i| i.text(name)).is_some() {
+            actions.push(ToKey::Notify {
+                notify: String::new(),
+                id: Some(name.to_string()),
+            });
+        }
+        ToKey::Multiple(actions)
+    }
+
+    /// Re-arm the auto-exit timeout on a mapping that is active in this mode
+    fn with_timeout(&self, name: &str, mapping: &Mapping) -> Mapping {
+        let mut mapping = mapping.clone();
+        // Tap dance already uses the delayed action (and `delay`)
+        let uses_delayed = mapping.to_delayed.is_some() || mapping.taps.is_some();
+        if let (Some(timeout), false) = (self.timeout, uses_delayed) {
+            mapping.to_delayed = Some(DelayedAction {
+                invoked: Some(self.exit_action(name)),
+                canceled: None,
+            });
+            mapping.delay = mapping.delay.or(Some(timeout));
+        }
+        mapping
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Unmapped {
+    /// Unmapped keys behave as usual
+    #[default]
+    Pass,
+    /// Unmapped keys do nothing while the mode is on
+    Swallow,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sequence {
+    /// Keys pressed one after another; sequences may share a prefix
+    pub keys: Vec<FromKey>,
+    pub to: ToKey,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SimlayerKeys {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Default for SimlayerKeys {
+    fn default() -> Self {
+        SimlayerKeys::Multiple(Vec::new())
+    }
+}
+
+impl SimlayerKeys {
+    pub fn to_vec(&self) -> Vec<String> {
+        match self {
+            SimlayerKeys::Single(key) => vec![key.clone()],
+            SimlayerKeys::Multiple(keys) => keys.clone(),
+        }
+    }
+}
+
+/// `true` shows the layer name, a string shows that text
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Indicator {
+    Enabled(bool),
+    Text(String),
+}
+
+impl Indicator {
+    fn text(&self, name: &str) -> Option<String> {
+        match self {
+            Indicator::Enabled(true) => Some(name.to_string()),
+            Indicator::Enabled(false) => None,
+            Indicator::Text(text) => Some(text.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Device {
+    #[serde(default)]
+    pub vendor_id: Option<u32>,
+    #[serde(default)]
+    pub product_id: Option<u32>,
+    #[serde(default)]
+    pub location_id: Option<u32>,
+    #[serde(default)]
+    pub is_keyboard: Option<bool>,
+    #[serde(default)]
+    pub is_pointing_device: Option<bool>,
+    #[serde(default)]
+    pub is_built_in_keyboard: Option<bool>,
+}
+
+impl Device {
+    fn to_identifier(&self) -> DeviceIdentifier {
+        DeviceIdentifier {
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            location_id: self.location_id,
+            is_keyboard: self.is_keyboard,
+            is_pointing_device: self.is_pointing_device,
+            is_built_in_keyboard: self.is_built_in_keyboard,
+        }
+    }
+}
+
+/// Input source matcher; Karabiner treats each field as a regex
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InputSource {
+    #[serde(default)]
+    pub language: Option<String>,
+    #[serde(default)]
+    pub input_source_id: Option<String>,
+    #[serde(default)]
+    pub input_mode_id: Option<String>,
+}
+
+impl InputSource {
+    fn to_specifier(&self) -> InputSourceSpecifier {
+        InputSourceSpecifier {
+            language: self.language.clone(),
+            input_source_id: self.input_source_id.clone(),
+            input_mode_id: self.input_mode_id.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -54,8 +335,17 @@ pub struct UserRule {
     pub description: String,
     #[serde(default)]
     pub layer: Option<String>,
+    /// Mode these mappings are active in
+    #[serde(default)]
+    pub mode: Option<String>,
     #[serde(default)]
     pub condition: Option<UserCondition>,
+    /// Additional conditions, all of which must hold (together with `condition`)
+    #[serde(default)]
+    pub conditions: Vec<UserCondition>,
+    /// Only apply on these physical layouts
+    #[serde(default)]
+    pub keyboard_type: Option<KeyboardTypes>,
     pub mappings: Vec<Mapping>,
 }
 
@@ -64,30 +354,260 @@ pub struct UserRule {
 pub enum UserCondition {
     App { app: String },
     Variable { variable: String, value: serde_json::Value },
+    Device { device: DeviceRef },
+    InputSource { input_source: InputSourceRef },
+    KeyboardType { keyboard_type: KeyboardTypes },
+    /// Karabiner expression, e.g. `count >= 2 && mode == 1`
+    Expression { expression: String },
+    All { all: Vec<UserCondition> },
+    Any { any: Vec<UserCondition> },
+    Not { not: Box<UserCondition> },
+}
+
+/// A device given by name (from `devices`), a list of names (any of them), or inline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DeviceRef {
+    Name(String),
+    Names(Vec<String>),
+    Inline(Device),
+}
+
+/// An input source given by name (from `input_sources`), a list of names (any of them), or inline
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum InputSourceRef {
+    Name(String),
+    Names(Vec<String>),
+    Inline(InputSource),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum KeyboardTypes {
+    Single(KeyboardType),
+    Multiple(Vec<KeyboardType>),
+}
+
+impl KeyboardTypes {
+    pub fn to_vec(&self) -> Vec<KeyboardType> {
+        match self {
+            KeyboardTypes::Single(t) => vec![*t],
+            KeyboardTypes::Multiple(v) => v.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Mapping {
     pub from: FromKey,
-    pub to: ToKey,
+    /// Required unless the mapping uses `taps` or `tap`/`hold`
+    #[serde(default)]
+    pub to: Option<ToKey>,
+    /// Actions for one, two, three... taps within the `delay` timeout (tap dance); another
+    /// key pressed within `delay` drops the pending tap
+    #[serde(default)]
+    pub taps: Option<Vec<ToKey>>,
+    /// Dual role: action when the key is tapped alone (use with `hold`)
+    #[serde(default)]
+    pub tap: Option<ToKey>,
+    /// Dual role: action while the key is held (use with `tap`)
+    #[serde(default)]
+    pub hold: Option<ToKey>,
     #[serde(default)]
     pub to_if_alone: Option<ToKey>,
     #[serde(default)]
     pub to_if_held: Option<ToKey>,
+    /// Action after the `delay` timeout, unless another key is pressed first
+    #[serde(default)]
+    pub to_delayed: Option<DelayedAction>,
+    /// Action when the key is released
+    #[serde(default)]
+    pub to_after_key_up: Option<ToKey>,
+    /// Only apply on these physical layouts (in addition to the rule's conditions)
+    #[serde(default)]
+    pub keyboard_type: Option<KeyboardTypes>,
+    /// Override profile `alone` (to_if_alone timeout, ms) for this mapping
+    #[serde(default)]
+    pub alone: Option<u32>,
+    /// Override profile `held` (to_if_held threshold, ms) for this mapping
+    #[serde(default)]
+    pub held: Option<u32>,
+    /// Override profile `delay` (to_delayed_action delay, ms) for this mapping
+    #[serde(default)]
+    pub delay: Option<u32>,
+}
+
+impl Mapping {
+    fn new(from: FromKey, to: ToKey) -> Self {
+        Mapping {
+            from,
+            to: Some(to),
+            taps: None,
+            tap: None,
+            hold: None,
+            to_if_alone: None,
+            to_if_held: None,
+            to_delayed: None,
+            to_after_key_up: None,
+            keyboard_type: None,
+            alone: None,
+            held: None,
+            delay: None,
+        }
+    }
+
+    /// Every action this mapping can emit
+    fn actions(&self) -> Vec<&ToKey> {
+        let mut actions: Vec<&ToKey> = self.to.iter().collect();
+        actions.extend(self.taps.iter().flatten());
+        actions.extend(&self.tap);
+        actions.extend(&self.hold);
+        actions.extend(&self.to_if_alone);
+        actions.extend(&self.to_if_held);
+        actions.extend(&self.to_after_key_up);
+        if let FromKey::Chord(chord) = &self.from {
+            actions.extend(&chord.to_after_key_up);
+        }
+        if let Some(delayed) = &self.to_delayed {
+            actions.extend(&delayed.invoked);
+            actions.extend(&delayed.canceled);
+        }
+        actions
+    }
+
+    /// Manipulator parameters for this mapping's timing overrides
+    fn parameters(&self, simultaneous_threshold: Option<u32>) -> Option<ManipulatorParameters> {
+        let parameters = ManipulatorParameters {
+            simultaneous_threshold,
+            to_if_alone_timeout: self.alone,
+            to_if_held_down_threshold: self.held,
+            to_delayed_action_delay: self.delay,
+        };
+        let is_empty = parameters.simultaneous_threshold.is_none()
+            && parameters.to_if_alone_timeout.is_none()
+            && parameters.to_if_held_down_threshold.is_none()
+            && parameters.to_delayed_action_delay.is_none();
+        (!is_empty).then_some(parameters)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DelayedAction {
+    /// Sent when the delay passes without another key press
+    #[serde(default)]
+    pub invoked: Option<ToKey>,
+    /// Sent when another key is pressed before the delay passes
+    #[serde(default)]
+    pub canceled: Option<ToKey>,
+}
+
+/// A key name (classified by usage page) or a key on an explicit usage page
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Key {
+    Name(String),
+    Consumer { consumer_key: String },
+    AppleVendor { apple_vendor_key: String },
+    AppleTopCase { apple_top_case_key: String },
+}
+
+impl Key {
+    pub fn name(&self) -> &str {
+        match self {
+            Key::Name(name) => name,
+            Key::Consumer { consumer_key: name }
+            | Key::AppleVendor {
+                apple_vendor_key: name,
+            }
+            | Key::AppleTopCase {
+                apple_top_case_key: name,
+            } => name,
+        }
+    }
+
+    pub fn usage(&self) -> KeyUsage {
+        match self {
+            Key::Name(name) => KeyUsage::from_name(name),
+            Key::Consumer { consumer_key } => KeyUsage::Consumer(consumer_key.clone()),
+            Key::AppleVendor { apple_vendor_key } => {
+                KeyUsage::AppleVendorKeyboard(apple_vendor_key.clone())
+            }
+            Key::AppleTopCase { apple_top_case_key } => {
+                KeyUsage::AppleVendorTopCase(apple_top_case_key.clone())
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum FromKey {
-    Simple(String),
+    Simple(Key),
     WithModifiers {
-        key: String,
+        key: Key,
         #[serde(default)]
         modifiers: Option<Modifiers>,
         #[serde(default)]
         optional: Option<Vec<String>>,
     },
-    Simultaneous(Vec<String>),
+    Simultaneous(Vec<Key>),
+    Chord(Chord),
+}
+
+impl FromKey {
+    /// Simultaneous keys as a chord (a plain key list gets the default options)
+    fn chord(&self) -> Option<Chord> {
+        match self {
+            FromKey::Simultaneous(keys) => Some(Chord {
+                keys: keys.clone(),
+                ..Chord::default()
+            }),
+            FromKey::Chord(chord) => Some(chord.clone()),
+            FromKey::Simple(_) | FromKey::WithModifiers { .. } => None,
+        }
+    }
+}
+
+/// Simultaneous keys with explicit options, e.g. an order-sensitive chord
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Chord {
+    pub keys: Vec<Key>,
+    /// Key down order (default: insensitive)
+    #[serde(default)]
+    pub order: Option<KeyOrder>,
+    /// Key up order (default: insensitive)
+    #[serde(default)]
+    pub key_up_order: Option<KeyOrder>,
+    /// Release any or all keys to end the chord (default: any)
+    #[serde(default)]
+    pub key_up_when: Option<KeyUpWhen>,
+    /// Override profile `sim` (simultaneous threshold, ms) for this chord
+    #[serde(default)]
+    pub threshold: Option<u32>,
+    /// Modifiers that must be held with the chord
+    #[serde(default)]
+    pub modifiers: Option<Modifiers>,
+    /// Modifiers allowed with the chord (default: any, unless `modifiers` is set)
+    #[serde(default)]
+    pub optional: Option<Vec<String>>,
+    /// Sent when the chord keys are released
+    #[serde(default)]
+    pub to_after_key_up: Option<ToKey>,
+}
+
+/// Modifiers for a simultaneous `from`: optional defaults to any unless some are mandatory
+fn chord_modifiers(mandatory: &Option<Modifiers>, optional: &Option<Vec<String>>) -> FromModifiers {
+    let mandatory = mandatory.as_ref().map(Modifiers::to_vec);
+    let optional = match (optional, &mandatory) {
+        (Some(optional), _) => Some(optional.clone()),
+        (None, Some(_)) => None,
+        (None, None) => Some(vec!["any".to_string()]),
+    };
+    FromModifiers {
+        mandatory,
+        optional,
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -125,7 +645,24 @@ pub struct UserMouseKey {
 pub enum ToKey {
     Simple(String),
     WithModifiers {
-        key: String,
+        key: Key,
+        #[serde(default)]
+        modifiers: Option<Modifiers>,
+        #[serde(flatten)]
+        options: ToEventOptions,
+    },
+    ConsumerKey {
+        consumer_key: String,
+        #[serde(default)]
+        modifiers: Option<Modifiers>,
+    },
+    AppleVendorKey {
+        apple_vendor_key: String,
+        #[serde(default)]
+        modifiers: Option<Modifiers>,
+    },
+    AppleTopCaseKey {
+        apple_top_case_key: String,
         #[serde(default)]
         modifiers: Option<Modifiers>,
     },
@@ -137,6 +674,39 @@ pub enum ToKey {
     },
     PointingButton {
         pointing_button: String,
+        #[serde(default)]
+        modifiers: Option<Modifiers>,
+        #[serde(flatten)]
+        options: ToEventOptions,
+    },
+    SelectInputSource {
+        select_input_source: InputSourceRef,
+    },
+    SetVariable {
+        set_variable: SetVariable,
+    },
+    /// On-screen message; an empty string hides it
+    Notify {
+        notify: String,
+        #[serde(default)]
+        id: Option<String>,
+    },
+    /// One-shot modifiers, e.g. `{ sticky: { left_shift: "toggle" } }`
+    Sticky {
+        sticky: BTreeMap<String, StickyModifierState>,
+    },
+    /// Bundle identifier, or an absolute path to the app
+    OpenApplication {
+        open_application: String,
+    },
+    SetMouseCursorPosition {
+        set_mouse_cursor_position: MouseCursorPosition,
+    },
+    DoubleClick {
+        cg_event_double_click: DoubleClick,
+    },
+    SleepSystem {
+        iokit_power_management_sleep_system: SleepSystem,
     },
     Multiple(Vec<ToKey>),
 }
@@ -144,223 +714,1200 @@ pub enum ToKey {
 /// Convert user config to Karabiner rules
 pub fn to_karabiner_rules(config: &UserConfig) -> Result<Vec<Rule>> {
     let mut rules = Vec::new();
+    let variables = defined_variables(config);
+
+    for (name, simlayer) in &config.simlayers {
+        if simlayer.key.to_vec().is_empty() && simlayer.modifiers.is_none() {
+            bail!("Simlayer '{}' needs a key or modifiers", name);
+        }
+        validate_expressions(&simlayer.condition, &variables)
+            .with_context(|| format!("In simlayer '{}'", name))?;
+    }
+
+    let mut layers: Vec<_> = config.layers.iter().collect();
+    layers.sort_by_key(|(name, _)| *name);
+    for (name, layer) in layers {
+        rules.push(convert_layer(name, layer, config)?);
+    }
+
+    let mut modes: Vec<_> = config.modes.iter().collect();
+    modes.sort_by_key(|(name, _)| *name);
+    for (name, mode) in &modes {
+        rules.push(convert_mode(name, mode, config)?);
+    }
+
+    if !config.sequences.is_empty() {
+        rules.push(convert_sequences(config)?);
+    }
 
     for user_rule in &config.rules {
+        validate_expressions(user_rule.condition.iter().chain(&user_rule.conditions), &variables)
+            .with_context(|| format!("In rule '{}'", user_rule.description))?;
         let rule = convert_rule(user_rule, config)?;
         rules.push(rule);
     }
 
+    // Swallowing must come after every rule that maps keys in the mode
+    for (name, mode) in &modes {
+        if mode.unmapped == Unmapped::Swallow {
+            rules.push(swallow_unmapped(name, mode, config)?);
+        }
+    }
+
     Ok(rules)
 }
 
-fn convert_rule(user_rule: &UserRule, config: &UserConfig) -> Result<Rule> {
-    let mut manipulators = Vec::new();
+/// Names of all variables the config sets (layers, modes, sequences and `set_variable` actions)
+fn defined_variables(config: &UserConfig) -> HashSet<String> {
+    fn collect(to: &ToKey, variables: &mut HashSet<String>) {
+        match to {
+            ToKey::SetVariable { set_variable } => {
+                variables.insert(set_variable.name.clone());
+            }
+            ToKey::Multiple(keys) => keys.iter().for_each(|k| collect(k, variables)),
+            _ => {}
+        }
+    }
 
-    // Check if this rule uses a simlayer
-    let simlayer = user_rule
-        .layer
-        .as_ref()
-        .and_then(|name| config.simlayers.get(name).map(|s| (name, s)));
+    let mut variables: HashSet<String> = config
+        .simlayers
+        .keys()
+        .chain(config.layers.keys())
+        .cloned()
+        .collect();
+    for (name, mode) in &config.modes {
+        variables.insert(mode.variable(name).to_string());
+    }
+    if !config.sequences.is_empty() {
+        variables.insert(SEQUENCE_VARIABLE.to_string());
+    }
+    for mapping in config.rules.iter().flat_map(|r| &r.mappings) {
+        for to in mapping.actions() {
+            collect(to, &mut variables);
+        }
+    }
+    for sequence in &config.sequences {
+        collect(&sequence.to, &mut variables);
+    }
+    for simlayer in config.simlayers.values() {
+        for to in simlayer.on_enter.iter().chain(&simlayer.on_exit) {
+            collect(to, &mut variables);
+        }
+    }
+    for layer in config.layers.values() {
+        if let Some(alone) = &layer.alone {
+            collect(alone, &mut variables);
+        }
+    }
+    variables
+}
 
-    for mapping in &user_rule.mappings {
-        let manips = convert_mapping(mapping, simlayer, &config.profile, &user_rule.condition)?;
-        manipulators.extend(manips);
+/// Check that every expression condition parses and only uses variables the config sets
+fn validate_expressions<'a>(
+    conditions: impl IntoIterator<Item = &'a UserCondition>,
+    variables: &HashSet<String>,
+) -> Result<()> {
+    fn visit(condition: &UserCondition, variables: &HashSet<String>) -> Result<()> {
+        match condition {
+            UserCondition::Expression { expression } => {
+                let referenced = expression::referenced_variables(expression)
+                    .with_context(|| format!("Invalid expression '{}'", expression))?;
+                if let Some(name) = referenced.iter().find(|v| !variables.contains(*v)) {
+                    bail!(
+                        "Expression '{}' uses variable '{}' which is never set",
+                        expression,
+                        name
+                    );
+                }
+                Ok(())
+            }
+            UserCondition::All { all: conditions } | UserCondition::Any { any: conditions } => {
+                conditions.iter().try_for_each(|c| visit(c, variables))
+            }
+            UserCondition::Not { not } => visit(not, variables),
+            _ => Ok(()),
+        }
+    }
+
+    conditions.into_iter().try_for_each(|c| visit(c, variables))
+}
+
+/// Hold layer: the key sets the layer variable while held and sends itself when tapped
+fn convert_layer(name: &str, layer: &Layer, config: &UserConfig) -> Result<Rule> {
+    let key = layer.key.usage();
+    let alone = match &layer.alone {
+        Some(alone) => convert_to_events(alone, config)?,
+        None => vec![key_event(key.clone(), None, ToEventOptions::default())],
+    };
+
+    let mut layer_on = vec![set_variable(name, 1)];
+    let mut layer_off = vec![set_variable(name, 0)];
+    if let Some(text) = layer.indicator.as_ref().and_then(|i| i.text(name)) {
+        layer_on.push(notification(name, &text));
+        layer_off.push(notification(name, ""));
     }
 
     Ok(Rule {
-        description: user_rule.description.clone(),
-        manipulators,
+        description: format!("{} (layer)", name),
+        manipulators: vec![Manipulator {
+            manipulator_type: "basic".to_string(),
+            from: FromEvent::KeyCode(FromKeyCode {
+                key,
+                modifiers: Some(FromModifiers {
+                    optional: Some(vec!["any".to_string()]),
+                    mandatory: None,
+                }),
+            }),
+            to: Some(layer_on),
+            to_if_alone: Some(alone),
+            to_if_held_down: None,
+            to_after_key_up: Some(layer_off),
+            to_delayed_action: None,
+            conditions: None,
+            parameters: None,
+        }],
     })
 }
 
-fn convert_mapping(
-    mapping: &Mapping,
-    simlayer: Option<(&String, &Simlayer)>,
-    profile: &ProfileSettings,
-    condition: &Option<UserCondition>,
-) -> Result<Vec<Manipulator>> {
+/// Mode entry and exit keys; exit keys come first so one key can toggle the mode
+fn convert_mode(name: &str, mode: &Mode, config: &UserConfig) -> Result<Rule> {
+    if mode.timeout.is_some() && mode.unmapped == Unmapped::Pass {
+        // A passed-through key cancels the pending auto-exit and nothing can re-arm it
+        bail!("Mode '{}' has a timeout, which needs unmapped: \"swallow\"", name);
+    }
+
+    let mut manipulators = Vec::new();
+    let in_mode = [Condition::VariableIf {
+        name: mode.variable(name).to_string(),
+        value: 1.into(),
+    }];
+
+    for exit in &mode.exit {
+        let mapping = Mapping::new(exit.clone(), mode.exit_action(name));
+        manipulators.extend(convert_mapping(&mapping, None, config, &in_mode)?);
+    }
+    for enter in &mode.enter {
+        let mapping = Mapping::new(enter.clone(), mode.enter_action(name));
+        let mapping = mode.with_timeout(name, &mapping);
+        manipulators.extend(convert_mapping(&mapping, None, config, &[])?);
+    }
+
+    Ok(Rule {
+        description: format!("{} (mode)", name),
+        manipulators,
+    })
+}
+
+/// Modifier keys stay usable in a swallowing mode so modified mappings still work
+const MODIFIER_KEYS: &[&str] = &[
+    "left_command",
+    "right_command",
+    "left_control",
+    "right_control",
+    "left_option",
+    "right_option",
+    "left_shift",
+    "right_shift",
+    "fn",
+];
+
+/// Drop keys that no rule maps while the mode is on
+fn swallow_unmapped(name: &str, mode: &Mode, config: &UserConfig) -> Result<Rule> {
+    let in_mode = Condition::VariableIf {
+        name: mode.variable(name).to_string(),
+        value: 1.into(),
+    };
+    // Every key press cancels the pending auto-exit, so the catch-all re-arms it. With a
+    // timeout, modified keys are swallowed too: passing them through would stop the timer
+    let modifiers = mode.timeout.map(|_| FromModifiers {
+        optional: Some(vec!["any".to_string()]),
+        mandatory: None,
+    });
+    let timeout = match mode.timeout {
+        Some(timeout) => {
+            let exit = DelayedAction {
+                invoked: Some(mode.exit_action(name)),
+                canceled: None,
+            };
+            Some((convert_delayed_action(&exit, config)?, timeout))
+        }
+        None => None,
+    };
+    let vk_none = KeyUsage::from_name("vk_none");
+    Ok(Rule {
+        description: format!("{} (mode, unmapped keys)", name),
+        manipulators: catch_unmapped(
+            in_mode,
+            modifiers,
+            vec![key_event(vk_none, None, ToEventOptions::default())],
+            timeout,
+        ),
+    })
+}
+
+/// Catch-all for keys no earlier manipulator matched; modifier keys pass through. A pending
+/// timeout is canceled by any key press, so `timeout` re-arms it on every catch-all manipulator
+fn catch_unmapped(
+    condition: Condition,
+    modifiers: Option<FromModifiers>,
+    to: Vec<ToEvent>,
+    timeout: Option<(ToDelayedAction, u32)>,
+) -> Vec<Manipulator> {
+    let (to_delayed_action, delay) = timeout.unzip();
+    let manipulator = |from: FromEvent, to: Vec<ToEvent>| Manipulator {
+        manipulator_type: "basic".to_string(),
+        from,
+        to: Some(to),
+        to_if_alone: None,
+        to_if_held_down: None,
+        to_after_key_up: None,
+        to_delayed_action: to_delayed_action.clone(),
+        conditions: Some(vec![condition.clone()]),
+        parameters: delay.map(|delay| ManipulatorParameters {
+            to_delayed_action_delay: Some(delay),
+            ..Default::default()
+        }),
+    };
+
+    let mut manipulators: Vec<Manipulator> = MODIFIER_KEYS
+        .iter()
+        .map(|key| {
+            let key = KeyUsage::from_name(key);
+            let from = FromEvent::KeyCode(FromKeyCode {
+                key: key.clone(),
+                modifiers: Some(FromModifiers {
+                    optional: Some(vec!["any".to_string()]),
+                    mandatory: None,
+                }),
+            });
+            manipulator(from, vec![key_event(key, None, ToEventOptions::default())])
+        })
+        .collect();
+    manipulators.push(manipulator(
+        FromEvent::Any(FromAny {
+            any: "key_code".to_string(),
+            modifiers,
+        }),
+        to,
+    ));
+    manipulators
+}
+
+/// Variable holding the current position in a sequence (0 when idle)
+const SEQUENCE_VARIABLE: &str = "kar_sequence";
+
+/// Sequences share one state variable: every proper prefix gets its own state, each step
+/// only matches in its prefix's state, and a pending prefix resets after the `sequence`
+/// timeout or on any key that doesn't continue it
+fn convert_sequences(config: &UserConfig) -> Result<Rule> {
+    enum Next<'a> {
+        State(u32),
+        Action(&'a ToKey),
+    }
+    struct Step<'a> {
+        state: u32,
+        from: &'a FromKey,
+        id: String,
+        next: Next<'a>,
+    }
+
+    let mut steps: Vec<Step> = Vec::new();
+    let mut last_state = 0;
+    for sequence in &config.sequences {
+        let ids = sequence
+            .keys
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        let describe = ids.join(", ");
+        let Some((last, prefix)) = sequence.keys.split_last() else {
+            bail!("Sequence must have at least one key");
+        };
+
+        let mut state = 0;
+        for (from, id) in prefix.iter().zip(&ids) {
+            let existing = steps.iter().find(|s| s.state == state && &s.id == id);
+            state = match existing {
+                Some(Step {
+                    next: Next::State(next),
+                    ..
+                }) => *next,
+                Some(_) => bail!("Sequence [{}] extends a shorter sequence", describe),
+                None => {
+                    last_state += 1;
+                    steps.push(Step {
+                        state,
+                        from,
+                        id: id.clone(),
+                        next: Next::State(last_state),
+                    });
+                    last_state
+                }
+            };
+        }
+
+        let id = &ids[ids.len() - 1];
+        if steps.iter().any(|s| s.state == state && &s.id == id) {
+            bail!("Sequence [{}] is a duplicate or a prefix of another sequence", describe);
+        }
+        steps.push(Step {
+            state,
+            from: last,
+            id: id.clone(),
+            next: Next::Action(&sequence.to),
+        });
+    }
+
+    let set_state = |value: u32| ToKey::SetVariable {
+        set_variable: SetVariable {
+            name: SEQUENCE_VARIABLE.to_string(),
+            value: value.into(),
+        },
+    };
     let mut manipulators = Vec::new();
+    for step in &steps {
+        let mapping = match &step.next {
+            Next::State(next) => Mapping {
+                to_delayed: Some(DelayedAction {
+                    invoked: Some(set_state(0)),
+                    canceled: None,
+                }),
+                delay: Some(config.profile.sequence),
+                ..Mapping::new(step.from.clone(), set_state(*next))
+            },
+            Next::Action(to) => {
+                Mapping::new(step.from.clone(), ToKey::Multiple(vec![set_state(0), (*to).clone()]))
+            }
+        };
+        let in_state = [Condition::VariableIf {
+            name: SEQUENCE_VARIABLE.to_string(),
+            value: step.state.into(),
+        }];
+        manipulators.extend(convert_mapping(&mapping, None, config, &in_state)?);
+    }
+
+    let pending = Condition::VariableUnless {
+        name: SEQUENCE_VARIABLE.to_string(),
+        value: 0.into(),
+    };
+    let any_modifiers = FromModifiers {
+        optional: Some(vec!["any".to_string()]),
+        mandatory: None,
+    };
+    // A modifier press cancels the step's reset, so the pass-throughs re-arm it
+    let reset = ToDelayedAction {
+        to_if_invoked: Some(vec![set_variable(SEQUENCE_VARIABLE, 0)]),
+        to_if_canceled: None,
+    };
+    manipulators.extend(catch_unmapped(
+        pending,
+        Some(any_modifiers),
+        vec![set_variable(SEQUENCE_VARIABLE, 0)],
+        Some((reset, config.profile.sequence)),
+    ));
+
+    Ok(Rule {
+        description: "sequences".to_string(),
+        manipulators,
+    })
+}
+
+fn convert_rule(user_rule: &UserRule, config: &UserConfig) -> Result<Rule> {
+    let mut manipulators = Vec::new();
+
+    let mut rule_conditions: Vec<UserCondition> = user_rule
+        .condition
+        .iter()
+        .chain(&user_rule.conditions)
+        .cloned()
+        .collect();
+    if let Some(keyboard_type) = &user_rule.keyboard_type {
+        rule_conditions.push(UserCondition::KeyboardType {
+            keyboard_type: keyboard_type.clone(),
+        });
+    }
+
+    let layer = match &user_rule.layer {
+        Some(name) => Some(if let Some(simlayer) = config.simlayers.get(name) {
+            RuleLayer::Sim(name, simlayer)
+        } else if config.layers.contains_key(name) {
+            RuleLayer::Hold(name)
+        } else {
+            bail!("Rule '{}' uses unknown layer '{}'", user_rule.description, name);
+        }),
+        None => None,
+    };
+    if let Some(RuleLayer::Sim(_, simlayer)) = layer {
+        rule_conditions.extend(simlayer.condition.clone());
+    }
+
+    let mode = match &user_rule.mode {
+        Some(name) => {
+            let mode = config.modes.get(name).with_context(|| {
+                format!("Rule '{}' uses unknown mode '{}'", user_rule.description, name)
+            })?;
+            rule_conditions.push(UserCondition::Variable {
+                variable: mode.variable(name).to_string(),
+                value: 1.into(),
+            });
+            Some((name, mode))
+        }
+        None => None,
+    };
+
+    for mapping in &user_rule.mappings {
+        let mapping = &match mode {
+            Some((name, mode)) => mode.with_timeout(name, mapping),
+            None => mapping.clone(),
+        };
+        let mut all = rule_conditions.clone();
+        if let Some(keyboard_type) = &mapping.keyboard_type {
+            all.push(UserCondition::KeyboardType {
+                keyboard_type: keyboard_type.clone(),
+            });
+        }
+
+        // Karabiner ANDs manipulator conditions, so `any` is emitted as one
+        // manipulator per alternative
+        let alternatives = expand_condition(&UserCondition::All { all }, false, config)?;
+        if alternatives.is_empty() {
+            bail!("Conditions of rule '{}' can never match", user_rule.description);
+        }
+
+        for conditions in &alternatives {
+            let manips = convert_mapping(mapping, layer, config, conditions)?;
+            manipulators.extend(manips);
+        }
+    }
+
+    Ok(Rule {
+        description: user_rule.description.clone(),
+        manipulators,
+    })
+}
 
-    // Build base condition from user condition
-    let mut conditions: Option<Vec<Condition>> = condition.as_ref().map(|c| match c {
+/// Expand a user condition into alternatives (OR of ANDs), pushing `not` down
+/// to the leaves where it becomes the `*_unless` form
+fn expand_condition(
+    condition: &UserCondition,
+    negate: bool,
+    config: &UserConfig,
+) -> Result<Vec<Vec<Condition>>> {
+    Ok(match condition {
         UserCondition::App { app } => {
-            vec![Condition::FrontmostAppIf {
-                bundle_identifiers: Some(vec![app.clone()]),
-                file_paths: None,
-            }]
+            let bundle_identifiers = Some(vec![app.clone()]);
+            let leaf = if negate {
+                Condition::FrontmostAppUnless {
+                    bundle_identifiers,
+                    file_paths: None,
+                }
+            } else {
+                Condition::FrontmostAppIf {
+                    bundle_identifiers,
+                    file_paths: None,
+                }
+            };
+            vec![vec![leaf]]
         }
         UserCondition::Variable { variable, value } => {
-            vec![Condition::VariableIf {
-                name: variable.clone(),
-                value: value.clone(),
-            }]
+            let (name, value) = (variable.clone(), value.clone());
+            let leaf = if negate {
+                Condition::VariableUnless { name, value }
+            } else {
+                Condition::VariableIf { name, value }
+            };
+            vec![vec![leaf]]
         }
-    });
+        UserCondition::Device { device } => {
+            let identifiers = resolve_devices(device, config)?;
+            let leaf = if negate {
+                Condition::DeviceUnless { identifiers }
+            } else {
+                Condition::DeviceIf { identifiers }
+            };
+            vec![vec![leaf]]
+        }
+        UserCondition::InputSource { input_source } => {
+            let input_sources = resolve_input_sources(input_source, config)?;
+            let leaf = if negate {
+                Condition::InputSourceUnless { input_sources }
+            } else {
+                Condition::InputSourceIf { input_sources }
+            };
+            vec![vec![leaf]]
+        }
+        UserCondition::KeyboardType { keyboard_type } => {
+            let keyboard_types = keyboard_type.to_vec();
+            let leaf = if negate {
+                Condition::KeyboardTypeUnless { keyboard_types }
+            } else {
+                Condition::KeyboardTypeIf { keyboard_types }
+            };
+            vec![vec![leaf]]
+        }
+        UserCondition::Expression { expression } => {
+            let expression = expression.clone();
+            let leaf = if negate {
+                Condition::ExpressionUnless { expression }
+            } else {
+                Condition::ExpressionIf { expression }
+            };
+            vec![vec![leaf]]
+        }
+        UserCondition::All { all } if !negate => expand_all(all, false, config)?,
+        UserCondition::All { all } => expand_any(all, true, config)?,
+        UserCondition::Any { any } if !negate => expand_any(any, false, config)?,
+        UserCondition::Any { any } => expand_all(any, true, config)?,
+        UserCondition::Not { not } => expand_condition(not, !negate, config)?,
+    })
+}
+
+fn expand_all(
+    conditions: &[UserCondition],
+    negate: bool,
+    config: &UserConfig,
+) -> Result<Vec<Vec<Condition>>> {
+    let mut alternatives = vec![vec![]];
+    for c in conditions {
+        let expanded = expand_condition(c, negate, config)?;
+        alternatives = alternatives
+            .iter()
+            .flat_map(|prefix| {
+                expanded.iter().map(move |alt| {
+                    let mut conds = prefix.clone();
+                    conds.extend(alt.iter().cloned());
+                    conds
+                })
+            })
+            .collect();
+    }
+    Ok(alternatives)
+}
+
+fn expand_any(
+    conditions: &[UserCondition],
+    negate: bool,
+    config: &UserConfig,
+) -> Result<Vec<Vec<Condition>>> {
+    let mut alternatives = Vec::new();
+    for c in conditions {
+        alternatives.extend(expand_condition(c, negate, config)?);
+    }
+    Ok(alternatives)
+}
+
+fn resolve_devices(device: &DeviceRef, config: &UserConfig) -> Result<Vec<DeviceIdentifier>> {
+    let lookup = |name: &String| {
+        config
+            .devices
+            .get(name)
+            .map(Device::to_identifier)
+            .with_context(|| format!("Unknown device '{}'", name))
+    };
+    match device {
+        DeviceRef::Name(name) => Ok(vec![lookup(name)?]),
+        DeviceRef::Names(names) => names.iter().map(lookup).collect(),
+        DeviceRef::Inline(device) => Ok(vec![device.to_identifier()]),
+    }
+}
+
+fn resolve_input_sources(
+    input_source: &InputSourceRef,
+    config: &UserConfig,
+) -> Result<Vec<InputSourceSpecifier>> {
+    let lookup = |name: &String| {
+        config
+            .input_sources
+            .get(name)
+            .map(InputSource::to_specifier)
+            .with_context(|| format!("Unknown input source '{}'", name))
+    };
+    match input_source {
+        InputSourceRef::Name(name) => Ok(vec![lookup(name)?]),
+        InputSourceRef::Names(names) => names.iter().map(lookup).collect(),
+        InputSourceRef::Inline(input_source) => Ok(vec![input_source.to_specifier()]),
+    }
+}
 
-    match &mapping.from {
-        FromKey::Simultaneous(keys) => {
+/// The layer a rule's mappings are active in
+#[derive(Clone, Copy)]
+enum RuleLayer<'a> {
+    /// Simlayer: every mapping also gets a simultaneous trigger manipulator
+    Sim(&'a str, &'a Simlayer),
+    /// Hold layer: the variable is set by the layer's own rule
+    Hold(&'a str),
+}
+
+impl RuleLayer<'_> {
+    fn name(&self) -> &str {
+        match self {
+            RuleLayer::Sim(name, _) | RuleLayer::Hold(name) => name,
+        }
+    }
+
+    /// Add the simlayer's modifiers as mandatory, so Karabiner strips them from the keys
+    /// pressed while the layer is on
+    fn key_modifiers(&self, modifiers: FromModifiers) -> FromModifiers {
+        match self {
+            RuleLayer::Sim(_, Simlayer { modifiers: Some(layer), .. }) => {
+                let mut mandatory = layer.to_vec();
+                mandatory.extend(modifiers.mandatory.into_iter().flatten());
+                FromModifiers {
+                    mandatory: Some(mandatory),
+                    optional: modifiers.optional,
+                }
+            }
+            _ => modifiers,
+        }
+    }
+}
+
+fn convert_mapping(
+    mapping: &Mapping,
+    layer: Option<RuleLayer>,
+    config: &UserConfig,
+    conditions: &[Condition],
+) -> Result<Vec<Manipulator>> {
+    if let Some(taps) = &mapping.taps {
+        return convert_taps(mapping, taps, layer, config, conditions);
+    }
+    if mapping.tap.is_some() || mapping.hold.is_some() {
+        return convert_mapping(&dual_role(mapping)?, layer, config, conditions);
+    }
+
+    let mut manipulators = Vec::new();
+    let profile = &config.profile;
+
+    let Some(to) = &mapping.to else {
+        bail!(
+            "Mapping from {} needs 'to', 'taps' or 'tap'/'hold'",
+            serde_json::to_string(&mapping.from)?
+        );
+    };
+    let to = convert_to_events(to, config)?;
+    let to_if_alone = mapping
+        .to_if_alone
+        .as_ref()
+        .map(|t| convert_to_events(t, config))
+        .transpose()?;
+    let to_if_held_down = mapping
+        .to_if_held
+        .as_ref()
+        .map(|t| convert_to_events(t, config))
+        .transpose()?;
+    let to_after_key_up = mapping
+        .to_after_key_up
+        .as_ref()
+        .map(|t| convert_to_events(t, config))
+        .transpose()?;
+    let to_delayed_action = mapping
+        .to_delayed
+        .as_ref()
+        .map(|d| convert_delayed_action(d, config))
+        .transpose()?;
+
+    let mut conditions = (!conditions.is_empty()).then(|| conditions.to_vec());
+    // The simlayer trigger only needs the rule's own conditions
+    let rule_conditions = conditions.clone();
+    if let Some(layer) = layer {
+        // Add layer variable condition (mappings apply while the layer is on)
+        conditions.get_or_insert_with(Vec::new).push(Condition::VariableIf {
+            name: layer.name().to_string(),
+            value: serde_json::Value::Number(1.into()),
+        });
+    }
+
+    let (keys, manipulator) = match mapping.from.chord() {
+        Some(chord) => {
             // Simultaneous key press (e.g., j+k together)
+            let chord_after_key_up = chord
+                .to_after_key_up
+                .as_ref()
+                .map(|t| convert_to_events(t, config))
+                .transpose()?;
+            let keys: Vec<KeyUsage> = chord.keys.iter().map(Key::usage).collect();
+            let modifiers = chord_modifiers(&chord.modifiers, &chord.optional);
+            let modifiers = match layer {
+                Some(layer) => layer.key_modifiers(modifiers),
+                None => modifiers,
+            };
             let from = FromEvent::Simultaneous(FromSimultaneous {
-                simultaneous: keys.iter().map(|k| SimultaneousKey { key_code: k.clone() }).collect(),
+                simultaneous: keys.iter().map(|k| SimultaneousKey { key: k.clone() }).collect(),
                 simultaneous_options: Some(SimultaneousOptions {
                     detect_key_down_uninterruptedly: Some(true),
-                    key_down_order: Some("insensitive".to_string()),
-                    key_up_order: Some("insensitive".to_string()),
-                    key_up_when: Some("any".to_string()),
-                    to_after_key_up: None,
-                }),
-                modifiers: Some(FromModifiers {
-                    optional: Some(vec!["any".to_string()]),
-                    mandatory: None,
+                    key_down_order: Some(chord.order.unwrap_or(KeyOrder::Insensitive)),
+                    key_up_order: Some(chord.key_up_order.unwrap_or(KeyOrder::Insensitive)),
+                    key_up_when: Some(chord.key_up_when.unwrap_or(KeyUpWhen::Any)),
+                    to_after_key_up: chord_after_key_up,
                 }),
+                modifiers: Some(modifiers),
             });
 
-            manipulators.push(Manipulator {
+            let manipulator = Manipulator {
                 manipulator_type: "basic".to_string(),
                 from,
-                to: Some(convert_to_events(&mapping.to)),
-                to_if_alone: mapping.to_if_alone.as_ref().map(|t| convert_to_events(t)),
-                to_if_held_down: mapping.to_if_held.as_ref().map(|t| convert_to_events(t)),
-                to_after_key_up: None,
-                conditions: conditions.clone(),
-                parameters: Some(ManipulatorParameters {
-                    simultaneous_threshold: Some(profile.sim),
-                }),
-            });
+                to: Some(to),
+                to_if_alone,
+                to_if_held_down,
+                to_after_key_up,
+                to_delayed_action,
+                conditions,
+                parameters: mapping.parameters(Some(chord.threshold.unwrap_or(profile.sim))),
+            };
+            (keys, manipulator)
         }
-        _ => {
+        None => {
             // Single key or key with modifiers
-            let (key_code, from_mods) = match &mapping.from {
-                FromKey::Simple(key) => (key.clone(), None),
+            let (key, from_mods) = match &mapping.from {
+                FromKey::Simple(key) => (key.usage(), None),
                 FromKey::WithModifiers { key, modifiers, optional } => {
                     let mods = FromModifiers {
                         mandatory: modifiers.as_ref().map(|m| m.to_vec()),
                         optional: optional.clone(),
                     };
-                    (key.clone(), Some(mods))
+                    (key.usage(), Some(mods))
                 }
-                FromKey::Simultaneous(_) => unreachable!(),
+                FromKey::Simultaneous(_) | FromKey::Chord(_) => unreachable!(),
             };
 
-            if let Some((layer_name, layer)) = simlayer {
-                // This is a simlayer mapping - create simultaneous triggers
-                let var_name = layer_name.clone();
-
-                // Add layer variable condition
-                let layer_condition = Condition::VariableIf {
-                    name: var_name.clone(),
-                    value: serde_json::Value::Number(1.into()),
-                };
-                match &mut conditions {
-                    Some(conds) => conds.push(layer_condition),
-                    None => conditions = Some(vec![layer_condition]),
-                }
+            // Keys inside a layer accept any modifiers unless the mapping says otherwise, and
+            // must have the simlayer's modifiers
+            let from_mods = match layer {
+                Some(layer) => Some(layer.key_modifiers(from_mods.unwrap_or(FromModifiers {
+                    optional: Some(vec!["any".to_string()]),
+                    mandatory: None,
+                }))),
+                None => from_mods,
+            };
+            let from = FromEvent::KeyCode(FromKeyCode {
+                key: key.clone(),
+                modifiers: from_mods,
+            });
 
-                // Regular mapping with layer condition (activated when layer is on)
-                let from = FromEvent::KeyCode(FromKeyCode {
-                    key_code: key_code.clone(),
-                    modifiers: from_mods.clone().or(Some(FromModifiers {
-                        optional: Some(vec!["any".to_string()]),
-                        mandatory: None,
-                    })),
-                });
-
-                manipulators.push(Manipulator {
-                    manipulator_type: "basic".to_string(),
-                    from,
-                    to: Some(convert_to_events(&mapping.to)),
-                    to_if_alone: mapping.to_if_alone.as_ref().map(|t| convert_to_events(t)),
-                    to_if_held_down: mapping.to_if_held.as_ref().map(|t| convert_to_events(t)),
-                    to_after_key_up: None,
-                    conditions: conditions.clone(),
-                    parameters: None,
-                });
-
-                // Simultaneous trigger (layer key + this key activates layer)
-                let sim_from = FromEvent::Simultaneous(FromSimultaneous {
-                    simultaneous: vec![
-                        SimultaneousKey { key_code: layer.key.clone() },
-                        SimultaneousKey { key_code: key_code.clone() },
-                    ],
-                    simultaneous_options: Some(SimultaneousOptions {
-                        detect_key_down_uninterruptedly: Some(true),
-                        key_down_order: Some("strict".to_string()),
-                        key_up_order: Some("strict_inverse".to_string()),
-                        key_up_when: Some("any".to_string()),
-                        to_after_key_up: Some(vec![ToEvent::SetVariable(ToSetVariable {
-                            set_variable: SetVariable {
-                                name: var_name.clone(),
-                                value: serde_json::Value::Number(0.into()),
-                            },
-                        })]),
-                    }),
-                    modifiers: Some(FromModifiers {
-                        optional: Some(vec!["any".to_string()]),
-                        mandatory: None,
-                    }),
-                });
-
-                let mut to_events = vec![ToEvent::SetVariable(ToSetVariable {
-                    set_variable: SetVariable {
-                        name: var_name,
-                        value: serde_json::Value::Number(1.into()),
-                    },
-                })];
-                to_events.extend(convert_to_events(&mapping.to));
-
-                manipulators.push(Manipulator {
-                    manipulator_type: "basic".to_string(),
-                    from: sim_from,
-                    to: Some(to_events),
-                    to_if_alone: None,
-                    to_if_held_down: None,
-                    to_after_key_up: None,
-                    conditions: None,
-                    parameters: Some(ManipulatorParameters {
-                        simultaneous_threshold: Some(layer.threshold.unwrap_or(profile.sim)),
-                    }),
-                });
-            } else {
-                // Simple mapping without layer
-                let from = FromEvent::KeyCode(FromKeyCode {
-                    key_code,
-                    modifiers: from_mods,
-                });
-
-                manipulators.push(Manipulator {
-                    manipulator_type: "basic".to_string(),
-                    from,
-                    to: Some(convert_to_events(&mapping.to)),
-                    to_if_alone: mapping.to_if_alone.as_ref().map(|t| convert_to_events(t)),
-                    to_if_held_down: mapping.to_if_held.as_ref().map(|t| convert_to_events(t)),
-                    to_after_key_up: None,
-                    conditions,
-                    parameters: None,
-                });
+            let manipulator = Manipulator {
+                manipulator_type: "basic".to_string(),
+                from,
+                to: Some(to),
+                to_if_alone,
+                to_if_held_down,
+                to_after_key_up,
+                to_delayed_action,
+                conditions,
+                parameters: mapping.parameters(None),
+            };
+            (vec![key], manipulator)
+        }
+    };
+
+    // Simlayers are also triggered by pressing the layer key and this key
+    // (or chord) together
+    let trigger = match layer {
+        Some(RuleLayer::Sim(name, simlayer)) => Some(simlayer_trigger(
+            name,
+            simlayer,
+            keys,
+            &manipulator,
+            rule_conditions,
+            config,
+        )?),
+        _ => None,
+    };
+    manipulators.push(manipulator);
+    manipulators.extend(trigger);
+
+    Ok(manipulators)
+}
+
+/// Simultaneous trigger for a simlayer mapping: the layer key plus the mapped key(s) turn the
+/// layer on and send the mapping's `to`. The layer key must come first, except for chords
+/// whose own key order is not strict
+fn simlayer_trigger(
+    name: &str,
+    simlayer: &Simlayer,
+    keys: Vec<KeyUsage>,
+    mapped: &Manipulator,
+    conditions: Option<Vec<Condition>>,
+    config: &UserConfig,
+) -> Result<Manipulator> {
+    // Layer on/off events, with the optional on-screen indicator
+    let mut layer_on = vec![set_variable(name, 1)];
+    let mut layer_off = vec![set_variable(name, 0)];
+    if let Some(text) = simlayer.indicator.as_ref().and_then(|i| i.text(name)) {
+        layer_on.push(notification(name, &text));
+        layer_off.push(notification(name, ""));
+    }
+    if let Some(on_enter) = &simlayer.on_enter {
+        layer_on.extend(convert_to_events(on_enter, config)?);
+    }
+    if let Some(on_exit) = &simlayer.on_exit {
+        layer_off.extend(convert_to_events(on_exit, config)?);
+    }
+
+    let layer_keys = simlayer.key.to_vec();
+    let modifiers = chord_modifiers(&simlayer.modifiers, &simlayer.optional);
+    let mut to_after_key_up = mapped.to_after_key_up.clone();
+    let mut simultaneous_threshold = Some(simlayer.threshold.unwrap_or(config.profile.sim));
+
+    let from = if layer_keys.is_empty() {
+        // Modifier-only layer: the mapped key (or chord) pressed with the layer's modifiers
+        let mut after = layer_off;
+        after.extend(to_after_key_up.into_iter().flatten());
+        to_after_key_up = Some(after);
+        match &mapped.from {
+            FromEvent::KeyCode(from) => {
+                simultaneous_threshold = None;
+                FromEvent::KeyCode(FromKeyCode {
+                    modifiers: Some(modifiers),
+                    ..from.clone()
+                })
+            }
+            FromEvent::Simultaneous(from) => {
+                simultaneous_threshold =
+                    mapped.parameters.as_ref().and_then(|p| p.simultaneous_threshold);
+                FromEvent::Simultaneous(FromSimultaneous {
+                    modifiers: Some(modifiers),
+                    ..from.clone()
+                })
             }
+            FromEvent::Any(_) => unreachable!(),
         }
+    } else {
+        let chord = match &mapped.from {
+            FromEvent::Simultaneous(from) => from.simultaneous_options.as_ref(),
+            _ => None,
+        };
+        // A multi-key layer can't require one of its keys to come first
+        let strict = layer_keys.len() == 1
+            && matches!(chord.and_then(|c| c.key_down_order), None | Some(KeyOrder::Strict));
+        let (key_down_order, key_up_order) = if strict {
+            (KeyOrder::Strict, KeyOrder::StrictInverse)
+        } else {
+            (KeyOrder::Insensitive, KeyOrder::Insensitive)
+        };
+        if let Some(after) = chord.and_then(|c| c.to_after_key_up.as_ref()) {
+            layer_off.extend(after.iter().cloned());
+        }
+
+        let simultaneous = layer_keys
+            .iter()
+            .map(|key| KeyUsage::from_name(key))
+            .chain(keys)
+            .map(|key| SimultaneousKey { key })
+            .collect();
+        FromEvent::Simultaneous(FromSimultaneous {
+            simultaneous,
+            simultaneous_options: Some(SimultaneousOptions {
+                detect_key_down_uninterruptedly: Some(
+                    simlayer.detect_key_down_uninterruptedly.unwrap_or(true),
+                ),
+                key_down_order: Some(simlayer.key_down_order.unwrap_or(key_down_order)),
+                key_up_order: Some(simlayer.key_up_order.unwrap_or(key_up_order)),
+                key_up_when: Some(chord.and_then(|c| c.key_up_when).unwrap_or(KeyUpWhen::Any)),
+                to_after_key_up: Some(layer_off),
+            }),
+            modifiers: Some(modifiers),
+        })
+    };
+
+    let mut to = layer_on;
+    to.extend(mapped.to.iter().flatten().cloned());
+    let to_delayed_action_delay = mapped
+        .parameters
+        .as_ref()
+        .and_then(|p| p.to_delayed_action_delay);
+    let parameters = (simultaneous_threshold.is_some() || to_delayed_action_delay.is_some())
+        .then_some(ManipulatorParameters {
+            simultaneous_threshold,
+            to_delayed_action_delay,
+            ..Default::default()
+        });
+
+    Ok(Manipulator {
+        manipulator_type: "basic".to_string(),
+        from,
+        to: Some(to),
+        to_if_alone: None,
+        to_if_held_down: None,
+        to_after_key_up,
+        to_delayed_action: mapped.to_delayed_action.clone(),
+        conditions,
+        parameters,
+    })
+}
+
+/// Tap dance: a counter variable tracks taps of the key; the last tap fires at once, earlier
+/// counts fire once `delay` passes without another tap, and any other key resets the count
+fn convert_taps(
+    mapping: &Mapping,
+    taps: &[ToKey],
+    layer: Option<RuleLayer>,
+    config: &UserConfig,
+    conditions: &[Condition],
+) -> Result<Vec<Manipulator>> {
+    // Each tap count is its own manipulator, so per-key actions and timeouts have no place
+    let combined = [
+        ("to", mapping.to.is_some()),
+        ("tap", mapping.tap.is_some()),
+        ("hold", mapping.hold.is_some()),
+        ("to_if_alone", mapping.to_if_alone.is_some()),
+        ("to_if_held", mapping.to_if_held.is_some()),
+        ("to_delayed", mapping.to_delayed.is_some()),
+        ("to_after_key_up", mapping.to_after_key_up.is_some()),
+        ("alone", mapping.alone.is_some()),
+        ("held", mapping.held.is_some()),
+    ];
+    if let Some((field, _)) = combined.iter().find(|(_, set)| *set) {
+        bail!("A mapping can't have both '{}' and 'taps'", field);
+    }
+    if taps.is_empty() {
+        bail!("'taps' needs at least one action");
+    }
+    // Another key pressed within `delay` drops the pending tap, which would eat letters
+    // while typing, so plain typing keys need a layer or variable to scope the taps
+    let gated = layer.is_some()
+        || conditions.iter().any(|c| matches!(c, Condition::VariableIf { .. }));
+    if !gated && is_typing_key(&mapping.from) {
+        bail!(
+            "'taps' on typing key {} would lose taps while typing; use a layer or a \
+             non-typing key",
+            serde_json::to_string(&mapping.from)?
+        );
+    }
+
+    let variable = tap_variable(&mapping.from, layer);
+    let set_count = |value: usize| ToKey::SetVariable {
+        set_variable: SetVariable {
+            name: variable.clone(),
+            value: value.into(),
+        },
+    };
+
+    let mut manipulators = Vec::new();
+    // Highest count first, so a tap continues a running count before starting a new one
+    for (count, tap) in taps.iter().enumerate().rev() {
+        let fire = ToKey::Multiple(vec![set_count(0), tap.clone()]);
+        let step = if count + 1 < taps.len() {
+            Mapping {
+                to_delayed: Some(DelayedAction {
+                    invoked: Some(fire),
+                    canceled: Some(set_count(0)),
+                }),
+                delay: mapping.delay,
+                ..Mapping::new(mapping.from.clone(), set_count(count + 1))
+            }
+        } else {
+            Mapping::new(mapping.from.clone(), fire)
+        };
+
+        let mut conditions = conditions.to_vec();
+        conditions.push(Condition::VariableIf {
+            name: variable.clone(),
+            value: count.into(),
+        });
+        // Only the first tap can trigger a simlayer; later taps just need it to be active
+        let layer = match layer {
+            Some(RuleLayer::Sim(name, _)) if count > 0 => Some(RuleLayer::Hold(name)),
+            layer => layer,
+        };
+        manipulators.extend(convert_mapping(&step, layer, config, &conditions)?);
     }
 
     Ok(manipulators)
 }
 
-fn convert_to_events(to: &ToKey) -> Vec<ToEvent> {
-    match to {
-        ToKey::Simple(key) => {
-            vec![ToEvent::KeyCode(ToKeyCode {
-                key_code: key.clone(),
+/// Expand `{ from, tap, hold }` into the canonical dual-role mapping: a modifier hold is sent
+/// lazily on key down (so it only takes effect with another key), any other hold (including
+/// keys like f13) waits for the `held` threshold, and the tap is `to_if_alone`. There's no
+/// "permissive" hold: a pending `to_delayed_action` is also canceled by keys typed after the
+/// tap is released, so firing the hold on cancel would trigger it while typing
+fn dual_role(mapping: &Mapping) -> Result<Mapping> {
+    let (Some(tap), Some(hold)) = (&mapping.tap, &mapping.hold) else {
+        bail!("A dual-role mapping needs both 'tap' and 'hold'");
+    };
+    if mapping.to.is_some() || mapping.taps.is_some() {
+        bail!("'tap'/'hold' can't be combined with 'to' or 'taps'");
+    }
+
+    let from = match &mapping.from {
+        FromKey::Simple(key) => FromKey::WithModifiers {
+            key: key.clone(),
+            modifiers: None,
+            optional: Some(vec!["any".to_string()]),
+        },
+        from => from.clone(),
+    };
+    let mut dual = Mapping {
+        from,
+        to_if_alone: Some(tap.clone()),
+        tap: None,
+        hold: None,
+        ..mapping.clone()
+    };
+
+    let is_modifier = |name: &str| MODIFIER_KEYS.contains(&name);
+    match hold {
+        ToKey::Simple(name) if is_modifier(name) => {
+            dual.to = Some(ToKey::WithModifiers {
+                key: Key::Name(name.clone()),
                 modifiers: None,
-                lazy: None,
-                repeat: None,
-            })]
+                options: ToEventOptions {
+                    lazy: Some(true),
+                    ..ToEventOptions::default()
+                },
+            });
         }
-        ToKey::WithModifiers { key, modifiers } => {
-            vec![ToEvent::KeyCode(ToKeyCode {
-                key_code: key.clone(),
-                modifiers: modifiers.as_ref().map(|m| m.to_vec()),
-                lazy: None,
-                repeat: None,
-            })]
+        ToKey::WithModifiers { key: key @ Key::Name(name), modifiers, options }
+            if is_modifier(name) =>
+        {
+            dual.to = Some(ToKey::WithModifiers {
+                key: key.clone(),
+                modifiers: modifiers.clone(),
+                options: ToEventOptions {
+                    lazy: Some(true),
+                    ..options.clone()
+                },
+            });
+        }
+        _ => {
+            dual.to = Some(ToKey::Multiple(Vec::new()));
+            dual.to_if_held = Some(hold.clone());
+        }
+    }
+    Ok(dual)
+}
+
+/// Keys typed in normal text (letters, digits, punctuation, space), without a modifier
+/// other than shift
+fn is_typing_key(from: &FromKey) -> bool {
+    const PUNCTUATION: &[&str] = &[
+        "hyphen",
+        "equal_sign",
+        "open_bracket",
+        "close_bracket",
+        "backslash",
+        "non_us_pound",
+        "semicolon",
+        "quote",
+        "grave_accent_and_tilde",
+        "comma",
+        "period",
+        "slash",
+        "spacebar",
+    ];
+    let (key, modifiers) = match from {
+        FromKey::Simple(key) => (key, Vec::new()),
+        FromKey::WithModifiers { key, modifiers, .. } => {
+            (key, modifiers.iter().flat_map(Modifiers::to_vec).collect())
+        }
+        FromKey::Simultaneous(_) | FromKey::Chord(_) => return false,
+    };
+    let Key::Name(name) = key else {
+        return false;
+    };
+    let typed = (name.len() == 1 && name.chars().all(|c| c.is_ascii_alphanumeric()))
+        || PUNCTUATION.contains(&name.as_str());
+    typed && modifiers.iter().all(|m| m.ends_with("shift"))
+}
+
+/// Counter variable for a tap-dance key, unique per layer and key combination
+fn tap_variable(from: &FromKey, layer: Option<RuleLayer>) -> String {
+    let mut parts = vec!["kar_taps".to_string()];
+    parts.extend(layer.map(|l| l.name().to_string()));
+    match from {
+        FromKey::Simple(key) => parts.push(key.name().to_string()),
+        FromKey::WithModifiers { key, modifiers, .. } => {
+            parts.extend(modifiers.iter().flat_map(Modifiers::to_vec));
+            parts.push(key.name().to_string());
+        }
+        FromKey::Simultaneous(keys) | FromKey::Chord(Chord { keys, .. }) => {
+            parts.extend(keys.iter().map(|k| k.name().to_string()))
+        }
+    }
+    parts.join("_")
+}
+
+fn key_event(key: KeyUsage, modifiers: Option<Vec<String>>, options: ToEventOptions) -> ToEvent {
+    ToEvent::KeyCode(ToKeyCode {
+        key,
+        modifiers,
+        options,
+    })
+}
+
+fn set_variable(name: &str, value: impl Into<serde_json::Value>) -> ToEvent {
+    ToEvent::SetVariable(ToSetVariable {
+        set_variable: SetVariable {
+            name: name.to_string(),
+            value: value.into(),
+        },
+    })
+}
+
+const DEFAULT_NOTIFICATION_ID: &str = "kar";
+
+fn notification(id: &str, text: &str) -> ToEvent {
+    ToEvent::NotificationMessage(ToNotificationMessage {
+        set_notification_message: NotificationMessage {
+            id: id.to_string(),
+            text: text.to_string(),
+        },
+    })
+}
+
+fn software_function(function: SoftwareFunction) -> Vec<ToEvent> {
+    vec![ToEvent::SoftwareFunction(ToSoftwareFunction {
+        software_function: function,
+    })]
+}
+
+fn convert_delayed_action(delayed: &DelayedAction, config: &UserConfig) -> Result<ToDelayedAction> {
+    let convert = |to: &Option<ToKey>| {
+        to.as_ref()
+            .map(|t| convert_to_events(t, config))
+            .transpose()
+    };
+    Ok(ToDelayedAction {
+        to_if_invoked: convert(&delayed.invoked)?,
+        to_if_canceled: convert(&delayed.canceled)?,
+    })
+}
+
+fn convert_to_events(to: &ToKey, config: &UserConfig) -> Result<Vec<ToEvent>> {
+    Ok(match to {
+        ToKey::Simple(key) => {
+            vec![key_event(KeyUsage::from_name(key), None, ToEventOptions::default())]
+        }
+        ToKey::WithModifiers {
+            key,
+            modifiers,
+            options,
+        } => {
+            let modifiers = modifiers.as_ref().map(|m| m.to_vec());
+            vec![key_event(key.usage(), modifiers, options.clone())]
+        }
+        ToKey::ConsumerKey {
+            consumer_key,
+            modifiers,
+        } => {
+            let key = KeyUsage::Consumer(consumer_key.clone());
+            let modifiers = modifiers.as_ref().map(|m| m.to_vec());
+            vec![key_event(key, modifiers, ToEventOptions::default())]
+        }
+        ToKey::AppleVendorKey {
+            apple_vendor_key,
+            modifiers,
+        } => {
+            let key = KeyUsage::AppleVendorKeyboard(apple_vendor_key.clone());
+            let modifiers = modifiers.as_ref().map(|m| m.to_vec());
+            vec![key_event(key, modifiers, ToEventOptions::default())]
+        }
+        ToKey::AppleTopCaseKey {
+            apple_top_case_key,
+            modifiers,
+        } => {
+            let key = KeyUsage::AppleVendorTopCase(apple_top_case_key.clone());
+            let modifiers = modifiers.as_ref().map(|m| m.to_vec());
+            vec![key_event(key, modifiers, ToEventOptions::default())]
         }
         ToKey::Shell { shell } => {
             vec![ToEvent::ShellCommand(ToShellCommand {
@@ -378,13 +1925,85 @@ fn convert_to_events(to: &ToKey) -> Vec<ToEvent> {
                 },
             })]
         }
-        ToKey::PointingButton { pointing_button } => {
+        ToKey::PointingButton {
+            pointing_button,
+            modifiers,
+            options,
+        } => {
             vec![ToEvent::PointingButton(ToPointingButton {
                 pointing_button: pointing_button.clone(),
-                modifiers: None,
+                modifiers: modifiers.as_ref().map(|m| m.to_vec()),
+                options: options.clone(),
             })]
         }
-        ToKey::Multiple(keys) => keys.iter().flat_map(convert_to_events).collect(),
+        ToKey::SelectInputSource { select_input_source } => {
+            let mut specifiers = resolve_input_sources(select_input_source, config)?;
+            if specifiers.len() != 1 {
+                bail!("select_input_source takes a single input source");
+            }
+            vec![ToEvent::SelectInputSource(ToSelectInputSource {
+                select_input_source: specifiers.remove(0),
+            })]
+        }
+        ToKey::SetVariable { set_variable } => {
+            vec![ToEvent::SetVariable(ToSetVariable {
+                set_variable: set_variable.clone(),
+            })]
+        }
+        ToKey::Notify { notify, id } => {
+            let id = id.as_deref().unwrap_or(DEFAULT_NOTIFICATION_ID);
+            vec![notification(id, notify)]
+        }
+        ToKey::Sticky { sticky } => sticky
+            .iter()
+            .map(|(modifier, state)| {
+                ToEvent::StickyModifier(ToStickyModifier {
+                    sticky_modifier: BTreeMap::from([(modifier.clone(), *state)]),
+                })
+            })
+            .collect(),
+        ToKey::OpenApplication { open_application } => {
+            let is_path = open_application.starts_with('/');
+            software_function(SoftwareFunction::OpenApplication(OpenApplication {
+                bundle_identifier: (!is_path).then(|| open_application.clone()),
+                file_path: is_path.then(|| open_application.clone()),
+            }))
+        }
+        ToKey::SetMouseCursorPosition {
+            set_mouse_cursor_position,
+        } => software_function(SoftwareFunction::SetMouseCursorPosition(
+            set_mouse_cursor_position.clone(),
+        )),
+        ToKey::DoubleClick {
+            cg_event_double_click,
+        } => software_function(SoftwareFunction::CgEventDoubleClick(
+            cg_event_double_click.clone(),
+        )),
+        ToKey::SleepSystem {
+            iokit_power_management_sleep_system,
+        } => software_function(SoftwareFunction::IokitPowerManagementSleepSystem(
+            iokit_power_management_sleep_system.clone(),
+        )),
+        ToKey::Multiple(keys) => {
+            let mut events = Vec::new();
+            for key in keys {
+                events.extend(convert_to_events(key, config)?);
+            }
+            events
+        }
+    })
+}
+
+/// Convert profile settings to Karabiner complex_modifications parameters
+pub fn to_parameters(config: &UserConfig) -> Parameters {
+    let profile = &config.profile;
+    Parameters {
+        simultaneous_threshold: Some(profile.sim),
+        to_if_alone_timeout: Some(profile.alone),
+        to_if_held_down_threshold: Some(profile.held),
+        to_delayed_action_delay: Some(profile.delay),
+        mouse_motion_to_scroll_speed: Some(profile.scroll_speed),
+        other: Default::default(),
     }
 }
 
@@ -394,12 +2013,224 @@ pub fn to_simple_modifications(config: &UserConfig) -> Vec<SimpleModificationEnt
         .simple
         .iter()
         .map(|s| SimpleModificationEntry {
-            from: SimpleModificationKey {
-                key_code: s.from.clone(),
-            },
-            to: vec![SimpleModificationKey {
-                key_code: s.to.clone(),
-            }],
+            from: SimpleModificationKey { key: s.from.usage() },
+            to: vec![SimpleModificationKey { key: s.to.usage() }],
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(value: serde_json::Value) -> UserConfig {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn expression_with_unset_variable_is_rejected() {
+        let config = config(json!({
+            "rules": [{
+                "description": "r",
+                "condition": { "expression": "count >= 2" },
+                "mappings": [{ "from": "a", "to": "b" }]
+            }]
+        }));
+        let err = to_karabiner_rules(&config).unwrap_err();
+        assert!(format!("{:#}", err).contains("uses variable 'count' which is never set"));
+    }
+
+    #[test]
+    fn expression_with_set_variable_is_accepted() {
+        let config = config(json!({
+            "simlayers": { "nav": { "key": "s" } },
+            "rules": [{
+                "description": "r",
+                "condition": { "not": { "expression": "nav == 1 && count >= 2" } },
+                "mappings": [
+                    { "from": "a", "to": { "set_variable": { "name": "count", "value": 1 } } }
+                ]
+            }]
+        }));
+        assert!(to_karabiner_rules(&config).is_ok());
+    }
+
+    #[test]
+    fn layer_alone_sets_variable() {
+        let config = config(json!({
+            "layers": {
+                "caps": {
+                    "key": "caps_lock",
+                    "alone": { "set_variable": { "name": "x", "value": 1 } }
+                }
+            },
+            "rules": [{
+                "description": "r",
+                "condition": { "expression": "x == 1" },
+                "mappings": [{ "from": "a", "to": "b" }]
+            }]
+        }));
+        assert!(to_karabiner_rules(&config).is_ok());
+    }
+
+    #[test]
+    fn taps_with_other_actions_are_rejected() {
+        let config = config(json!({
+            "rules": [{
+                "description": "r",
+                "mappings": [{
+                    "from": "f5",
+                    "taps": ["a", "b"],
+                    "to_if_held": "c",
+                    "to_after_key_up": "d",
+                    "held": 300
+                }]
+            }]
+        }));
+        let err = to_karabiner_rules(&config).unwrap_err();
+        assert!(format!("{:#}", err).contains("can't have both 'to_if_held' and 'taps'"));
+    }
+
+    #[test]
+    fn mode_timeout_requires_swallow() {
+        let config = config(json!({
+            "modes": { "normal": { "enter": ["f13"], "timeout": 1000 } },
+            "rules": []
+        }));
+        assert!(to_karabiner_rules(&config).is_err());
+    }
+
+    #[test]
+    fn swallowed_keys_rearm_mode_timeout() {
+        let config = config(json!({
+            "modes": { "normal": { "enter": ["f13"], "timeout": 1000, "unmapped": "swallow" } },
+            "rules": []
+        }));
+        let rules = to_karabiner_rules(&config).unwrap();
+        let unmapped = rules.last().unwrap();
+        assert!(!unmapped.manipulators.is_empty());
+        for manipulator in &unmapped.manipulators {
+            let delayed = manipulator.to_delayed_action.as_ref().unwrap();
+            assert!(delayed.to_if_invoked.is_some());
+            let parameters = manipulator.parameters.as_ref().unwrap();
+            assert_eq!(parameters.to_delayed_action_delay, Some(1000));
+        }
+    }
+
+    #[test]
+    fn sequence_modifier_press_rearms_reset() {
+        let config = config(json!({
+            "sequences": [{ "keys": ["f13", "g"], "to": "escape" }],
+            "rules": []
+        }));
+        let rules = to_karabiner_rules(&config).unwrap();
+        let passthrough = rules[0]
+            .manipulators
+            .iter()
+            .find(|m| {
+                matches!(&m.from, FromEvent::KeyCode(from)
+                    if from.key == KeyUsage::Keyboard("left_shift".to_string()))
+            })
+            .unwrap();
+        let delayed = passthrough.to_delayed_action.as_ref().unwrap();
+        let reset = serde_json::to_value(&delayed.to_if_invoked).unwrap();
+        assert_eq!(reset, json!([{ "set_variable": { "name": "kar_sequence", "value": 0 } }]));
+        let parameters = passthrough.parameters.as_ref().unwrap();
+        assert_eq!(parameters.to_delayed_action_delay, Some(1000));
+    }
+
+    #[test]
+    fn invalid_expression_is_rejected() {
+        let variables = HashSet::from(["a".to_string()]);
+        let condition = UserCondition::Expression {
+            expression: "a ==".to_string(),
+        };
+        assert!(validate_expressions([&condition], &variables).is_err());
+    }
+
+    #[test]
+    fn mapping_without_action_is_rejected() {
+        let config = config(json!({
+            "rules": [{ "description": "r", "mappings": [{ "from": "b", "too": "c" }] }]
+        }));
+        let err = to_karabiner_rules(&config).unwrap_err();
+        assert!(format!("{:#}", err).contains("needs 'to', 'taps' or 'tap'/'hold'"));
+    }
+
+    #[test]
+    fn taps_on_typing_key_need_a_layer() {
+        let taps = json!({ "from": "q", "taps": ["q", "escape"] });
+        let unscoped = config(json!({
+            "rules": [{ "description": "r", "mappings": [taps.clone()] }]
+        }));
+        assert!(to_karabiner_rules(&unscoped).is_err());
+
+        let scoped = config(json!({
+            "simlayers": { "nav": { "key": "s" } },
+            "rules": [
+                { "description": "r", "layer": "nav", "mappings": [taps] },
+                { "description": "f", "mappings": [{ "from": "f5", "taps": ["f5", "f6"] }] }
+            ]
+        }));
+        assert!(to_karabiner_rules(&scoped).is_ok());
+    }
+
+    #[test]
+    fn simlayer_modifiers_are_mandatory_in_layer() {
+        let config = config(json!({
+            "simlayers": { "space-mode": { "key": "spacebar", "modifiers": "right_command" } },
+            "rules": [{
+                "description": "r",
+                "layer": "space-mode",
+                "mappings": [
+                    { "from": "j", "to": "down_arrow" },
+                    { "from": ["k", "l"], "to": "home" }
+                ]
+            }]
+        }));
+        let rules = to_karabiner_rules(&config).unwrap();
+        let in_layer: Vec<_> = rules[0]
+            .manipulators
+            .iter()
+            .filter(|m| m.conditions.is_some())
+            .map(|m| serde_json::to_value(&m.from).unwrap())
+            .collect();
+        assert_eq!(in_layer.len(), 2);
+        for from in in_layer {
+            assert_eq!(
+                from["modifiers"],
+                json!({ "mandatory": ["right_command"], "optional": ["any"] })
+            );
+        }
+    }
+
+    fn dual_role_manipulator(mapping: serde_json::Value) -> serde_json::Value {
+        let config = config(json!({ "rules": [{ "description": "r", "mappings": [mapping] }] }));
+        let rules = to_karabiner_rules(&config).unwrap();
+        serde_json::to_value(&rules[0].manipulators[0]).unwrap()
+    }
+
+    #[test]
+    fn modifier_hold_is_lazy() {
+        let manipulator = dual_role_manipulator(
+            json!({ "from": "caps_lock", "tap": "escape", "hold": "left_control" }),
+        );
+        assert_eq!(manipulator["to"], json!([{ "key_code": "left_control", "lazy": true }]));
+        assert_eq!(manipulator["to_if_alone"], json!([{ "key_code": "escape" }]));
+        assert!(manipulator.get("to_if_held_down").is_none());
+    }
+
+    #[test]
+    fn key_hold_waits_for_threshold() {
+        // Tapping then typing must not fire the hold: nothing but to_if_held_down sends it
+        let manipulator = dual_role_manipulator(
+            json!({ "from": "tab", "tap": "tab", "hold": "f13", "held": 200 }),
+        );
+        assert_eq!(manipulator["to"], json!([]));
+        assert_eq!(manipulator["to_if_alone"], json!([{ "key_code": "tab" }]));
+        assert_eq!(manipulator["to_if_held_down"], json!([{ "key_code": "f13" }]));
+        assert_eq!(manipulator["parameters"]["basic.to_if_held_down_threshold_milliseconds"], 200);
+        assert!(manipulator.get("to_delayed_action").is_none());
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::{bail, Result};
+
+// Minimal parser for Karabiner's expression syntax (exprtk subset), used only
+// to validate `expression_if` / `expression_unless` before writing the config
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number,
+    Str,
+    Ident(String),
+    Op(&'static str),
+}
+
+const OPERATORS: &[&str] = &[
+    "&&", "||", "==", "!=", "<>", "<=", ">=", "<", ">", "=", "+", "-", "*", "/", "%", "^", "!",
+    "(", ")", ",", "?", ":",
+];
+
+fn tokenize(expr: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            tokens.push(Token::Number);
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            tokens.push(match word.as_str() {
+                "and" => Token::Op("&&"),
+                "or" | "xor" => Token::Op("||"),
+                "not" => Token::Op("!"),
+                "true" | "false" => Token::Number,
+                _ => Token::Ident(word),
+            });
+        } else if c == '\'' {
+            i += 1;
+            while i < chars.len() && chars[i] != '\'' {
+                i += 1;
+            }
+            if i == chars.len() {
+                bail!("unterminated string");
+            }
+            i += 1;
+            tokens.push(Token::Str);
+        } else {
+            let rest: String = chars[i..].iter().take(2).collect();
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| anyhow::anyhow!("unexpected character '{}'", c))?;
+            i += op.len();
+            tokens.push(Token::Op(op));
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    variables: Vec<String>,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Op(op)) => Some(op),
+            _ => None,
+        }
+    }
+
+    fn eat(&mut self, ops: &[&str]) -> bool {
+        match self.peek_op() {
+            Some(op) if ops.contains(&op) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<()> {
+        if !self.eat(&[op]) {
+            bail!("expected '{}'", op);
+        }
+        Ok(())
+    }
+
+    fn binary(&mut self, ops: &[&str], next: fn(&mut Self) -> Result<()>) -> Result<()> {
+        next(self)?;
+        while self.eat(ops) {
+            next(self)?;
+        }
+        Ok(())
+    }
+
+    fn ternary(&mut self) -> Result<()> {
+        self.or()?;
+        if self.eat(&["?"]) {
+            self.ternary()?;
+            self.expect(":")?;
+            self.ternary()?;
+        }
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<()> {
+        self.binary(&["||"], Self::and)
+    }
+
+    fn and(&mut self) -> Result<()> {
+        self.binary(&["&&"], Self::comparison)
+    }
+
+    fn comparison(&mut self) -> Result<()> {
+        self.binary(&["==", "!=", "<>", "<=", ">=", "<", ">", "="], Self::additive)
+    }
+
+    fn additive(&mut self) -> Result<()> {
+        self.binary(&["+", "-"], Self::multiplicative)
+    }
+
+    fn multiplicative(&mut self) -> Result<()> {
+        self.binary(&["*", "/", "%"], Self::power)
+    }
+
+    fn power(&mut self) -> Result<()> {
+        self.binary(&["^"], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<()> {
+        if self.eat(&["!", "-", "+"]) {
+            return self.unary();
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<()> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Number) | Some(Token::Str) => Ok(()),
+            Some(Token::Ident(name)) => {
+                if self.eat(&["("]) {
+                    // Function call, e.g. max(a, b)
+                    if !self.eat(&[")"]) {
+                        self.binary(&[","], Self::ternary)?;
+                        self.expect(")")?;
+                    }
+                } else if !name.starts_with("system.") {
+                    self.variables.push(name);
+                }
+                Ok(())
+            }
+            Some(Token::Op("(")) => {
+                self.ternary()?;
+                self.expect(")")
+            }
+            Some(Token::Op(op)) => bail!("unexpected '{}'", op),
+            None => bail!("unexpected end of expression"),
+        }
+    }
+}
+
+/// Parse an expression and return the variable names it references
+pub fn referenced_variables(expr: &str) -> Result<Vec<String>> {
+    let mut parser = Parser {
+        tokens: tokenize(expr)?,
+        pos: 0,
+        variables: Vec::new(),
+    };
+    parser.ternary()?;
+    if parser.pos < parser.tokens.len() {
+        bail!("unexpected trailing input");
+    }
+    Ok(parser.variables)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collects_variables_across_precedence_levels() {
+        let vars = referenced_variables("a + b * c ^ 2 >= d && !e || f ? g : -h").unwrap();
+        assert_eq!(vars, ["a", "b", "c", "d", "e", "f", "g", "h"]);
+    }
+
+    #[test]
+    fn parses_parentheses_and_word_operators() {
+        let vars = referenced_variables("(a or b) and not (c == 1) xor true").unwrap();
+        assert_eq!(vars, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn skips_system_variables_and_function_names() {
+        let vars = referenced_variables("system.now.milliseconds - max(last, 0) > 500").unwrap();
+        assert_eq!(vars, ["last"]);
+    }
+
+    #[test]
+    fn accepts_string_literals() {
+        let vars = referenced_variables("mode == 'insert' && app != 'a b'").unwrap();
+        assert_eq!(vars, ["mode", "app"]);
+    }
+
+    #[test]
+    fn rejects_unterminated_string() {
+        assert!(referenced_variables("mode == 'insert").is_err());
+    }
+
+    #[test]
+    fn rejects_unterminated_input() {
+        assert!(referenced_variables("a &&").is_err());
+        assert!(referenced_variables("(a == 1").is_err());
+        assert!(referenced_variables("a ? b").is_err());
+        assert!(referenced_variables("").is_err());
+    }
+
+    #[test]
+    fn rejects_trailing_tokens() {
+        assert!(referenced_variables("a == 1 b").is_err());
+        assert!(referenced_variables("a )").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_characters() {
+        assert!(referenced_variables("a # b").is_err());
+    }
+}
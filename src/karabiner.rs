@@ -1,5 +1,6 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::path::Path;
 
 // Karabiner JSON types matching the official spec
@@ -42,7 +43,107 @@ pub struct SimpleModificationEntry {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimpleModificationKey {
-    pub key_code: String,
+    #[serde(flatten)]
+    pub key: KeyUsage,
+}
+
+/// A key on one of the usage pages Karabiner supports, serialized under the
+/// matching field name (`key_code`, `consumer_key_code`, ...)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyUsage {
+    #[serde(rename = "key_code")]
+    Keyboard(String),
+    #[serde(rename = "consumer_key_code")]
+    Consumer(String),
+    #[serde(rename = "apple_vendor_keyboard_key_code")]
+    AppleVendorKeyboard(String),
+    #[serde(rename = "apple_vendor_top_case_key_code")]
+    AppleVendorTopCase(String),
+}
+
+/// Key names emitted as `consumer_key_code` (media keys, app launchers). Some of these, e.g.
+/// `play_or_pause` or `mute`, are also valid `key_code`s; the consumer usage is what Apple
+/// keyboards send for them, so it takes precedence
+const CONSUMER_KEYS: &[&str] = &[
+    "play_or_pause",
+    "fastforward",
+    "rewind",
+    "scan_next_track",
+    "scan_previous_track",
+    "eject",
+    "mute",
+    "volume_increment",
+    "volume_decrement",
+    "display_brightness_increment",
+    "display_brightness_decrement",
+    "dictation",
+    "al_word_processor",
+    "al_text_editor",
+    "al_spreadsheet",
+    "al_graphics_editor",
+    "al_presentation_app",
+    "al_database_app",
+    "al_email_reader",
+    "al_voicemail",
+    "al_address_book",
+    "al_calendar",
+    "al_project_manager",
+    "al_journal",
+    "al_finance",
+    "al_calculator",
+    "al_a_v_capture_playback",
+    "al_local_machine_browser",
+    "al_lan_wan_browser",
+    "al_internet_browser",
+    "al_remote_networking",
+    "al_telephony",
+    "al_network_chat",
+    "al_instant_messaging",
+    "al_terminal_lock_or_screensaver",
+    "al_keyboard_layout",
+    "ac_search",
+    "ac_home",
+    "ac_back",
+    "ac_forward",
+    "ac_stop",
+    "ac_refresh",
+    "ac_bookmarks",
+];
+
+/// Apple vendor keyboard usages (Mission Control, Launchpad, Spotlight, ...)
+const APPLE_VENDOR_KEYBOARD_KEYS: &[&str] = &[
+    "mission_control",
+    "spotlight",
+    "dashboard",
+    "launchpad",
+    "expose_all",
+    "expose_desktop",
+    "language",
+];
+
+/// Apple vendor top case usages (the globe/fn key and keyboard illumination)
+const APPLE_VENDOR_TOP_CASE_KEYS: &[&str] = &[
+    "keyboard_fn",
+    "video_mirror",
+    "illumination_toggle",
+    "illumination_up",
+    "illumination_down",
+];
+
+impl KeyUsage {
+    /// Classify a plain key name by the usage page it belongs to
+    pub fn from_name(name: &str) -> Self {
+        let name = name.to_string();
+        if CONSUMER_KEYS.contains(&name.as_str()) {
+            KeyUsage::Consumer(name)
+        } else if APPLE_VENDOR_KEYBOARD_KEYS.contains(&name.as_str()) {
+            KeyUsage::AppleVendorKeyboard(name)
+        } else if APPLE_VENDOR_TOP_CASE_KEYS.contains(&name.as_str()) {
+            KeyUsage::AppleVendorTopCase(name)
+        } else {
+            KeyUsage::Keyboard(name)
+        }
+    }
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -75,6 +176,13 @@ pub struct Parameters {
         skip_serializing_if = "Option::is_none"
     )]
     pub to_delayed_action_delay: Option<u32>,
+    #[serde(
+        rename = "mouse_motion_to_scroll.speed",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub mouse_motion_to_scroll_speed: Option<u32>,
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -97,11 +205,21 @@ pub struct Manipulator {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub to_after_key_up: Option<Vec<ToEvent>>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_delayed_action: Option<ToDelayedAction>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub conditions: Option<Vec<Condition>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub parameters: Option<ManipulatorParameters>,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToDelayedAction {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_if_invoked: Option<Vec<ToEvent>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_if_canceled: Option<Vec<ToEvent>>,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ManipulatorParameters {
     #[serde(
@@ -109,6 +227,21 @@ pub struct ManipulatorParameters {
         skip_serializing_if = "Option::is_none"
     )]
     pub simultaneous_threshold: Option<u32>,
+    #[serde(
+        rename = "basic.to_if_alone_timeout_milliseconds",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub to_if_alone_timeout: Option<u32>,
+    #[serde(
+        rename = "basic.to_if_held_down_threshold_milliseconds",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub to_if_held_down_threshold: Option<u32>,
+    #[serde(
+        rename = "basic.to_delayed_action_delay_milliseconds",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub to_delayed_action_delay: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -116,11 +249,21 @@ pub struct ManipulatorParameters {
 pub enum FromEvent {
     KeyCode(FromKeyCode),
     Simultaneous(FromSimultaneous),
+    Any(FromAny),
+}
+
+/// Matches every event of one kind, e.g. `{ "any": "key_code" }`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FromAny {
+    pub any: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modifiers: Option<FromModifiers>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FromKeyCode {
-    pub key_code: String,
+    #[serde(flatten)]
+    pub key: KeyUsage,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub modifiers: Option<FromModifiers>,
 }
@@ -136,7 +279,8 @@ pub struct FromSimultaneous {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimultaneousKey {
-    pub key_code: String,
+    #[serde(flatten)]
+    pub key: KeyUsage,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -144,15 +288,32 @@ pub struct SimultaneousOptions {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub detect_key_down_uninterruptedly: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub key_down_order: Option<String>,
+    pub key_down_order: Option<KeyOrder>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub key_up_order: Option<String>,
+    pub key_up_order: Option<KeyOrder>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub key_up_when: Option<String>,
+    pub key_up_when: Option<KeyUpWhen>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub to_after_key_up: Option<Vec<ToEvent>>,
 }
 
+/// Order in which simultaneous keys must be pressed or released
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyOrder {
+    Insensitive,
+    Strict,
+    StrictInverse,
+}
+
+/// Whether releasing any or all simultaneous keys counts as the key up
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyUpWhen {
+    Any,
+    All,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct FromModifiers {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -165,11 +326,14 @@ pub struct FromModifiers {
 #[serde(untagged)]
 pub enum ToEvent {
     KeyCode(ToKeyCode),
-    ConsumerKeyCode(ToConsumerKeyCode),
     PointingButton(ToPointingButton),
     ShellCommand(ToShellCommand),
     SetVariable(ToSetVariable),
     MouseKey(ToMouseKey),
+    SelectInputSource(ToSelectInputSource),
+    SoftwareFunction(ToSoftwareFunction),
+    StickyModifier(ToStickyModifier),
+    NotificationMessage(ToNotificationMessage),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -177,24 +341,31 @@ pub struct ToPointingButton {
     pub pointing_button: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub modifiers: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub options: ToEventOptions,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ToConsumerKeyCode {
-    pub consumer_key_code: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub modifiers: Option<Vec<String>>,
+/// Options shared by key and pointing button to events
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToEventOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lazy: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repeat: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub halt: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hold_down_milliseconds: Option<u32>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToKeyCode {
-    pub key_code: String,
+    #[serde(flatten)]
+    pub key: KeyUsage,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub modifiers: Option<Vec<String>>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub lazy: Option<bool>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub repeat: Option<bool>,
+    #[serde(flatten)]
+    pub options: ToEventOptions,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -213,6 +384,80 @@ pub struct SetVariable {
     pub value: serde_json::Value,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToSelectInputSource {
+    pub select_input_source: InputSourceSpecifier,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToNotificationMessage {
+    pub set_notification_message: NotificationMessage,
+}
+
+/// On-screen message; an empty `text` hides the message with the same `id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotificationMessage {
+    pub id: String,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToStickyModifier {
+    /// Modifier name to state, e.g. `{ "left_shift": "toggle" }`
+    pub sticky_modifier: BTreeMap<String, StickyModifierState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StickyModifierState {
+    On,
+    Off,
+    Toggle,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToSoftwareFunction {
+    pub software_function: SoftwareFunction,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SoftwareFunction {
+    OpenApplication(OpenApplication),
+    SetMouseCursorPosition(MouseCursorPosition),
+    CgEventDoubleClick(DoubleClick),
+    IokitPowerManagementSleepSystem(SleepSystem),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenApplication {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bundle_identifier: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_path: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MouseCursorPosition {
+    /// Pixels, or a percentage of the screen such as "50%"
+    pub x: serde_json::Value,
+    pub y: serde_json::Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub screen: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DoubleClick {
+    #[serde(default)]
+    pub button: u32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SleepSystem {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay_milliseconds: Option<u32>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToMouseKey {
     pub mouse_key: MouseKey,
@@ -253,13 +498,64 @@ pub enum Condition {
         #[serde(skip_serializing_if = "Option::is_none")]
         file_paths: Option<Vec<String>>,
     },
+    #[serde(rename = "device_if")]
+    DeviceIf { identifiers: Vec<DeviceIdentifier> },
+    #[serde(rename = "device_unless")]
+    DeviceUnless { identifiers: Vec<DeviceIdentifier> },
+    #[serde(rename = "input_source_if")]
+    InputSourceIf { input_sources: Vec<InputSourceSpecifier> },
+    #[serde(rename = "input_source_unless")]
+    InputSourceUnless { input_sources: Vec<InputSourceSpecifier> },
+    #[serde(rename = "keyboard_type_if")]
+    KeyboardTypeIf { keyboard_types: Vec<KeyboardType> },
+    #[serde(rename = "keyboard_type_unless")]
+    KeyboardTypeUnless { keyboard_types: Vec<KeyboardType> },
+    #[serde(rename = "expression_if")]
+    ExpressionIf { expression: String },
+    #[serde(rename = "expression_unless")]
+    ExpressionUnless { expression: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyboardType {
+    Ansi,
+    Iso,
+    Jis,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DeviceIdentifier {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vendor_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location_id: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_keyboard: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_pointing_device: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_built_in_keyboard: Option<bool>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InputSourceSpecifier {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_source_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_mode_id: Option<String>,
 }
 
-/// Update a profile in karabiner.json with new rules
+/// Update a profile in karabiner.json with new rules and parameters
 pub fn update_profile(
     path: &Path,
     profile_name: &str,
     rules: Vec<Rule>,
+    parameters: Parameters,
     simple_modifications: Vec<SimpleModificationEntry>,
 ) -> Result<()> {
     let content = std::fs::read_to_string(path)
@@ -275,6 +571,9 @@ pub fn update_profile(
         .with_context(|| format!("Profile '{}' not found", profile_name))?;
 
     profile.complex_modifications.rules = rules;
+    // Keep any parameters kar doesn't manage
+    let other = std::mem::take(&mut profile.complex_modifications.parameters.other);
+    profile.complex_modifications.parameters = Parameters { other, ..parameters };
     if !simple_modifications.is_empty() {
         profile.simple_modifications = simple_modifications;
     }
@@ -1,11 +1,12 @@
 mod config;
+mod expression;
 mod karabiner;
 mod runtime;
 mod watch;
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn default_config_path() -> PathBuf {
     dirs::home_dir()
@@ -56,7 +57,7 @@ fn main() -> Result<()> {
     }
 }
 
-fn build(config_path: &PathBuf, dry_run: bool, profile: &str) -> Result<()> {
+fn build(config_path: &Path, dry_run: bool, profile: &str) -> Result<()> {
     // Execute TS config and get JSON
     let json = runtime::execute_config(config_path)
         .with_context(|| format!("Failed to execute {}", config_path.display()))?;
@@ -67,6 +68,7 @@ fn build(config_path: &PathBuf, dry_run: bool, profile: &str) -> Result<()> {
 
     // Convert to Karabiner format
     let rules = config::to_karabiner_rules(&user_config)?;
+    let parameters = config::to_parameters(&user_config);
     let simple_mods = config::to_simple_modifications(&user_config);
 
     if dry_run {
@@ -80,7 +82,7 @@ fn build(config_path: &PathBuf, dry_run: bool, profile: &str) -> Result<()> {
         .context("Could not find home directory")?
         .join(".config/karabiner/karabiner.json");
 
-    karabiner::update_profile(&karabiner_path, profile, rules, simple_mods)?;
+    karabiner::update_profile(&karabiner_path, profile, rules, parameters, simple_mods)?;
 
     println!("Updated profile '{}'", profile);
     Ok(())
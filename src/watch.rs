@@ -63,7 +63,8 @@ fn build_once(config_path: &Path, karabiner_path: &Path, profile: &str) -> Resul
     let json = runtime::execute_config(config_path)?;
     let user_config: config::UserConfig = serde_json::from_str(&json)?;
     let rules = config::to_karabiner_rules(&user_config)?;
+    let parameters = config::to_parameters(&user_config);
     let simple_mods = config::to_simple_modifications(&user_config);
-    karabiner::update_profile(karabiner_path, profile, rules, simple_mods)?;
+    karabiner::update_profile(karabiner_path, profile, rules, parameters, simple_mods)?;
     Ok(())
 }
@@ -35,6 +35,39 @@ export type KeyCode =
   | "print_screen" | "scroll_lock" | "pause" | "insert"
   | "application" | "help" | "power" | "execute" | "menu" | "select" | "stop" | "again" | "undo"
 
+// Consumer (media / app launch) usages; plain `to: "play_or_pause"` is emitted as consumer_key_code
+export type ConsumerKey =
+  | "play_or_pause" | "fastforward" | "rewind" | "scan_next_track" | "scan_previous_track" | "eject"
+  | "mute" | "volume_increment" | "volume_decrement"
+  | "display_brightness_increment" | "display_brightness_decrement" | "dictation"
+  | "al_word_processor" | "al_text_editor" | "al_spreadsheet" | "al_graphics_editor"
+  | "al_presentation_app" | "al_database_app" | "al_email_reader" | "al_voicemail"
+  | "al_address_book" | "al_calendar" | "al_project_manager" | "al_journal" | "al_finance"
+  | "al_calculator" | "al_a_v_capture_playback" | "al_local_machine_browser"
+  | "al_lan_wan_browser" | "al_internet_browser" | "al_remote_networking" | "al_telephony"
+  | "al_network_chat" | "al_instant_messaging" | "al_terminal_lock_or_screensaver"
+  | "al_keyboard_layout"
+  | "ac_search" | "ac_home" | "ac_back" | "ac_forward" | "ac_stop" | "ac_refresh" | "ac_bookmarks"
+
+// Apple vendor usages; plain names are emitted as apple_vendor_keyboard_key_code
+export type AppleVendorKey =
+  | "mission_control" | "spotlight" | "dashboard" | "launchpad"
+  | "expose_all" | "expose_desktop" | "language"
+
+// Apple top case usages; plain names are emitted as apple_vendor_top_case_key_code
+export type AppleTopCaseKey =
+  | "keyboard_fn" | "video_mirror" | "illumination_toggle" | "illumination_up" | "illumination_down"
+
+// Any key: a name (classified by usage page) or an explicit usage page
+export type Key =
+  | KeyCode
+  | ConsumerKey
+  | AppleVendorKey
+  | AppleTopCaseKey
+  | { consumer_key: string }
+  | { apple_vendor_key: string }
+  | { apple_top_case_key: string }
+
 export type Modifier =
   | "command" | "control" | "option" | "shift"
   | "left_command" | "right_command"
@@ -49,21 +82,82 @@ export interface ProfileSettings {
   alone?: number
   /** Threshold for simultaneous key detection in ms (default: 200) */
   sim?: number
+  /** Threshold for to_if_held in ms (default: 500) */
+  held?: number
+  /** Delay for to_delayed_action in ms (default: 500) */
+  delay?: number
+  /** Mouse motion to scroll speed (default: 100) */
+  scroll_speed?: number
+  /** Time allowed between the keys of a sequence in ms (default: 1000) */
+  sequence?: number
 }
 
+// Order in which simultaneous keys must be pressed or released
+export type KeyOrder = "insensitive" | "strict" | "strict_inverse"
+
+export type SimlayerKey = KeyCode | AppleVendorKey | AppleTopCaseKey
+
 // Simlayer definition
 export interface Simlayer {
-  /** The key that activates this simlayer */
-  key: KeyCode
+  /** The key that activates this simlayer, or keys pressed together (e.g. ["d", "f"]) */
+  key?: SimlayerKey | SimlayerKey[]
+  /** Modifiers that must be held with the layer key (or alone activate the layer if no key) */
+  modifiers?: Modifier | Modifier[]
+  /** Modifiers allowed with the layer key (default: any, unless `modifiers` is set) */
+  optional?: Modifier[]
   /** Optional custom threshold in ms */
   threshold?: number
+  /** Trigger key order (default: "strict", the layer key first) */
+  key_down_order?: KeyOrder
+  /** Trigger release order (default: "strict_inverse") */
+  key_up_order?: KeyOrder
+  /** Only trigger if no other key is pressed in between (default: true) */
+  detect_key_down_uninterruptedly?: boolean
+  /** Sent when the layer is activated */
+  on_enter?: ToKey
+  /** Sent when the layer is released */
+  on_exit?: ToKey
+  /** Only enable the layer where this holds (e.g. some apps or devices) */
+  condition?: Condition
+  /** Show a notification while active: true for the layer name, or custom text */
+  indicator?: boolean | string
+}
+
+// Hold layer: active while `key` is held, `key` is still sent when tapped
+export interface Layer {
+  /** The key that activates this layer while held */
+  key: Key
+  /** Sent when the key is tapped (default: the key itself) */
+  alone?: ToKey
+  /** Show a notification while active: true for the layer name, or custom text */
+  indicator?: boolean | string
+}
+
+// Simultaneous keys with explicit options
+export interface Chord {
+  keys: Key[]
+  /** Key down order (default: "insensitive") */
+  order?: KeyOrder
+  /** Key up order (default: "insensitive") */
+  key_up_order?: KeyOrder
+  /** Release any or all keys to end the chord (default: "any") */
+  key_up_when?: "any" | "all"
+  /** Override profile `sim` for this chord (ms) */
+  threshold?: number
+  /** Modifiers that must be held with the chord */
+  modifiers?: Modifier | Modifier[]
+  /** Modifiers allowed with the chord (default: any, unless `modifiers` is set) */
+  optional?: Modifier[]
+  /** Sent when the chord keys are released */
+  to_after_key_up?: ToKey
 }
 
 // From key specification
 export type FromKey =
-  | KeyCode
-  | { key: KeyCode; modifiers?: Modifier | Modifier[]; optional?: Modifier[] }
-  | KeyCode[] // Simultaneous keys
+  | Key
+  | { key: Key; modifiers?: Modifier | Modifier[]; optional?: Modifier[] }
+  | Key[] // Simultaneous keys
+  | Chord // Simultaneous keys with options
 
 // Mouse key specification
 export interface MouseKey {
@@ -77,50 +171,190 @@ export interface MouseKey {
 // Pointing button (mouse click)
 export type PointingButton = "button1" | "button2" | "button3"
 
+// Software functions (run by Karabiner itself, faster than a shell)
+export interface MouseCursorPosition {
+  /** Pixels, or a percentage of the screen such as "50%" */
+  x: number | string
+  y: number | string
+  screen?: number
+}
+
+// Options for key and pointing button events
+export interface ToEventOptions {
+  /** Only send the modifier together with the next key (for tap-hold modifiers) */
+  lazy?: boolean
+  /** Key repeat while held (default: true) */
+  repeat?: boolean
+  /** Stop the remaining events (e.g. to_if_alone after to_if_held_down) */
+  halt?: boolean
+  /** Hold the key for this long before releasing (ms) */
+  hold_down_milliseconds?: number
+}
+
 // To key specification
 export type ToKey =
   | KeyCode
-  | { key: KeyCode; modifiers?: Modifier | Modifier[] }
+  | ConsumerKey
+  | AppleVendorKey
+  | AppleTopCaseKey
+  | ({ key: Key; modifiers?: Modifier | Modifier[] } & ToEventOptions)
+  /** Always emitted as consumer_key_code */
+  | { consumer_key: string; modifiers?: Modifier | Modifier[] }
+  /** Always emitted as apple_vendor_keyboard_key_code */
+  | { apple_vendor_key: string; modifiers?: Modifier | Modifier[] }
+  /** Always emitted as apple_vendor_top_case_key_code */
+  | { apple_top_case_key: string; modifiers?: Modifier | Modifier[] }
   | { shell: string }
   | { mouse_key: MouseKey }
-  | { pointing_button: PointingButton }
+  | ({ pointing_button: PointingButton; modifiers?: Modifier | Modifier[] } & ToEventOptions)
+  /** Switch input source, by name (from `input_sources`) or inline */
+  | { select_input_source: string | InputSource }
+  | { set_variable: { name: string; value: number | boolean | string } }
+  /** On-screen message (empty text hides it); `id` defaults to "kar" */
+  | { notify: string; id?: string }
+  /** One-shot modifiers: the next key press gets the modifier */
+  | { sticky: Partial<Record<Modifier, "on" | "off" | "toggle">> }
+  /** Open an app by bundle identifier or absolute path */
+  | { open_application: string }
+  | { set_mouse_cursor_position: MouseCursorPosition }
+  /** Double click (button 0 is the primary button) */
+  | { cg_event_double_click: { button?: number } }
+  | { iokit_power_management_sleep_system: { delay_milliseconds?: number } }
   | ToKey[] // Multiple actions
 
+// Device identifiers (as shown in Karabiner-EventViewer > Devices)
+export interface Device {
+  vendor_id?: number
+  product_id?: number
+  location_id?: number
+  is_keyboard?: boolean
+  is_pointing_device?: boolean
+  is_built_in_keyboard?: boolean
+}
+
+// Input source matcher (each field is a regex)
+export interface InputSource {
+  language?: string
+  input_source_id?: string
+  input_mode_id?: string
+}
+
+// Physical keyboard layout
+export type KeyboardType = "ansi" | "iso" | "jis"
+
 // Condition for when a rule applies
 export type Condition =
   | { app: string }
   | { variable: string; value: number | boolean | string }
+  /** Device by name (from `devices`), any of several names, or inline */
+  | { device: string | string[] | Device }
+  /** Input source by name (from `input_sources`), any of several names, or inline */
+  | { input_source: string | string[] | InputSource }
+  | { keyboard_type: KeyboardType | KeyboardType[] }
+  /** Karabiner expression, e.g. "count >= 2 && mode == 1" (variables must be set in the config) */
+  | { expression: string }
+  /** Every condition must hold */
+  | { all: Condition[] }
+  /** At least one condition must hold */
+  | { any: Condition[] }
+  /** Negation (emitted as *_unless) */
+  | { not: Condition }
 
 // A single key mapping
 export interface Mapping {
   from: FromKey
-  to: ToKey
+  /** Action when the key is pressed (omit when using `taps` or `tap`/`hold`) */
+  to?: ToKey
+  /**
+   * Tap dance: actions for one, two, three... taps within `delay`. Another key pressed
+   * within `delay` drops the pending tap, so typing keys need a layer or variable condition
+   */
+  taps?: ToKey[]
+  /** Dual role: action when the key is tapped alone (use with `hold`) */
+  tap?: ToKey
+  /** Dual role: a modifier is held lazily, other actions fire after the `held` threshold */
+  hold?: ToKey
   /** Action when key is released quickly (tap) */
   to_if_alone?: ToKey
   /** Action when key is held down */
   to_if_held?: ToKey
+  /** Action when key is released */
+  to_after_key_up?: ToKey
+  /** Actions after the `delay` timeout (invoked) or when another key is pressed first (canceled) */
+  to_delayed?: { invoked?: ToKey; canceled?: ToKey }
+  /** Only apply on these keyboard layouts */
+  keyboard_type?: KeyboardType | KeyboardType[]
+  /** Override profile `alone` for this mapping (ms) */
+  alone?: number
+  /** Override profile `held` for this mapping (ms) */
+  held?: number
+  /** Override profile `delay` for this mapping (ms) */
+  delay?: number
 }
 
 // A rule containing multiple mappings
 export interface Rule {
   description: string
-  /** Simlayer name to use for these mappings */
+  /** Simlayer or hold layer name to use for these mappings */
   layer?: string
+  /** Mode name: mappings apply only while the mode is on */
+  mode?: string
   /** Condition for when this rule applies */
   condition?: Condition
+  /** Additional conditions, all of which must hold */
+  conditions?: Condition[]
+  /** Only apply on these keyboard layouts */
+  keyboard_type?: KeyboardType | KeyboardType[]
   mappings: Mapping[]
 }
 
+// Mode: stays on after an entry key until an exit key or timeout
+export interface Mode {
+  /** Variable set while the mode is on (default: the mode name) */
+  variable?: string
+  /** Keys that turn the mode on */
+  enter: FromKey[]
+  /** Keys that turn the mode off (default: escape) */
+  exit?: FromKey[]
+  /**
+   * Leave the mode after this many ms without a key press (requires unmapped: "swallow").
+   * Modified keys are swallowed too, and a key handled by a rule outside the mode (a layer,
+   * sequence or global rule) stops the timeout, so the mode stays on until an exit key
+   */
+  timeout?: number
+  /** Unmapped unmodified keys: "pass" (default) or "swallow" (with a timeout: all keys) */
+  unmapped?: "pass" | "swallow"
+  /** Show a notification while active: true for the mode name, or custom text */
+  indicator?: boolean | string
+}
+
+// Multi-stroke binding; sequences may share a prefix (e.g. a leader key)
+export interface Sequence {
+  /** Keys pressed one after another */
+  keys: FromKey[]
+  to: ToKey
+}
+
 // Simple modification (key remapping without conditions)
 export interface SimpleModification {
-  from: KeyCode
-  to: KeyCode
+  from: Key
+  to: Key
 }
 
 // Main config structure
 export interface Config {
   profile?: ProfileSettings
   simlayers?: Record<string, Simlayer>
+  /** Layers active while their key is held (like Goku's :layers) */
+  layers?: Record<string, Layer>
+  /** Modes that stay on until an exit key or timeout */
+  modes?: Record<string, Mode>
+  /** Leader-key style sequences, e.g. leader, g, s */
+  sequences?: Sequence[]
+  /** Named devices for use in `{ device: "name" }` conditions */
+  devices?: Record<string, Device>
+  /** Named input sources for conditions and `select_input_source` */
+  input_sources?: Record<string, InputSource>
   /** Simple key remappings (e.g., caps_lock -> escape) */
   simple?: SimpleModification[]
   rules: Rule[]
@@ -135,10 +369,18 @@ export function km(macroName: string): { shell: string } {
   return shell(`osascript -e 'tell application "Keyboard Maestro Engine" to do script "${macroName}"'`)
 }
 
-export function open(path: string): { shell: string } {
+export function open(path: string): { shell: string } | { open_application: string } {
+  // Apps open faster through software_function than through a shell
+  if (path.startsWith("/") && path.endsWith(".app")) {
+    return app(path)
+  }
   return shell(`open "${path}"`)
 }
 
+export function app(bundleIdOrPath: string): { open_application: string } {
+  return { open_application: bundleIdOrPath }
+}
+
 export function zed(path: string): { shell: string } {
   // Expand ~ to $HOME for shell
   const expandedPath = path.startsWith("~/") ? `$HOME${path.slice(1)}` : path